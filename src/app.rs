@@ -1,19 +1,29 @@
-use std::sync::Arc;
+use std::{collections::VecDeque, fs::File, io::BufWriter, path::Path, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
 
+use chrono::{Local, TimeZone};
 use egui::{RichText};
+use tokio::sync::oneshot::{self, error::TryRecvError};
 
 use crate::{
-    input::{InputWithTimestamp, InputStrings},
-    input_event_recorder::{GetResultError, InputRecorder, ReadNextStatusError},
-    input_player::{InputPlayer, InputReplayState, Repeating},
+    input::{select_lines, serialize_inputs, to_absolute_timing, to_relative_timing, DisplayMetrics, InputStrings, InputWithTimestamp, ScriptLine},
+    input_event::InputEventInfo,
+    event_log::{event_log_path, load_event_log, save_event_log, EventLog},
+    export::{export_script, used_displays, ExportError, ExportFormat, PythonTarget, ShellTarget},
+    input_event_recorder::{get_connected_devices, get_display_metrics, GetResultError, InputRecorder, ReadNextStatusError, StatusMessage},
+    lint::{lint_script, Diagnostic, Severity},
+    input_player::{InputPlayer, InputReplayState, LoggedCommand, PlayDevice, PlaybackSpeed, PlayerCommand, ReadNextResultError, Repeating, StartBarrier, StepResult, StopConditions, StopReason},
+    script::{expand_includes, load_script_file, serialize_script, ConversionSettings, ExpandedScript, Script, ScriptError, ScriptHeader},
 };
 
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
+// the size of each display by its id, None is the default display
+type DisplaySizes = Vec<(Option<i32>, DisplayMetrics)>;
+
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
 pub struct AirApp {
     #[serde(skip)]
-    input: Option<Arc<Vec<InputWithTimestamp>>>,
+    input: Option<Arc<Vec<ScriptLine>>>,
 
     #[serde(skip)]
     input_strings : Option<InputStrings>,
@@ -21,12 +31,178 @@ pub struct AirApp {
     #[serde(skip)]
     record_task: Option<InputRecorder>,
 
+    // what the running recording reported so far
+    #[serde(skip)]
+    recording_feed: RecordingFeed,
+
+    // one for every device played on, kept after playing to show the results
+    #[serde(skip)]
+    plays: Vec<DevicePlay>,
+
+    #[serde(skip)]
+    script_header: Option<ScriptHeader>,
+
+    // result of the last save or load, shown next to the buttons
+    #[serde(skip)]
+    file_status: Option<String>,
+
+    // why the script could not be played
     #[serde(skip)]
-    play_task: Option<InputPlayer>,
+    play_error: Option<String>,
+
+    // serial numbers of the connected devices, asked from adb with "Refresh devices"
+    #[serde(skip)]
+    devices: Vec<String>,
+
+    #[serde(skip)]
+    devices_task: Option<oneshot::Receiver<Option<Vec<String>>>>,
+
+    // receives the result of a running export and the display sizes it asked for
+    #[serde(skip)]
+    export_task: Option<oneshot::Receiver<(String, DisplaySizes)>>,
+
+    // display sizes asked from the devices when playing or exporting, by serial. Dry runs use them instead of asking again
+    #[serde(skip)]
+    known_display_metrics: Vec<(Option<String>, DisplaySizes)>,
+
+    // what the linter found in the script, shown below the lines they are about
+    #[serde(skip)]
+    diagnostics: Vec<Diagnostic>,
+
+    // line indices of the script to pause at
+    #[serde(skip)]
+    breakpoints: Vec<usize>,
+
+    // line indices of the script selected in the list, the last one is where shift-click selects from
+    #[serde(skip)]
+    selection: Vec<usize>,
+
+    // the raw events the script was converted from, None for scripts without one
+    #[serde(skip)]
+    event_log: Option<EventLog>,
+
+    // the script being played with its includes, maps the played lines back to where they were written
+    #[serde(skip)]
+    play_expansion: Option<ExpandedScript>,
 
     tap_threshold_ms : u32,
     tap_threshold_distance : u32,
     delay_ms_between_loops : u32,
+    // 0 repeats until stopped
+    repetitions : u32,
+    // 0 has no limit
+    max_minutes : u32,
+    // local time of the day
+    stop_at_enabled : bool,
+    stop_at_hour : u32,
+    stop_at_minute : u32,
+    stop_on_error : bool,
+    playback_speed : f32,
+    scale_swipe_durations : bool,
+    spin_wait : bool,
+    // seed of the last run, kept for the next one when fixed
+    random_seed : u64,
+    fixed_seed : bool,
+    normalize_coordinates : bool,
+    script_path : String,
+    // getevent log to import, kept apart from the script so saving never writes over it
+    import_path : String,
+    // devices to play on, none plays on the only connected device
+    play_devices : Vec<String>,
+    start_together : bool,
+}
+
+// a player for one device and what it reported
+struct DevicePlay {
+    // None for the only connected device
+    serial: Option<String>,
+    // None once playing ended
+    player: Option<InputPlayer>,
+    // how the run ended
+    result: Option<String>,
+    // steps sent and the ones of them that failed
+    step_count: usize,
+    failed_steps: Vec<StepResult>,
+    // what a dry run would have sent
+    command_log: Vec<LoggedCommand>,
+    // the display sizes the player knows, kept after it ended
+    display_metrics: DisplaySizes,
+}
+
+impl DevicePlay {
+    fn update(&mut self, expansion: Option<&ExpandedScript>) {
+        if let Some(player) = &mut self.player {
+            // results of steps still running arrive after stopping, the player is kept until all are read
+            let mut results_finished = false;
+            loop {
+                match player.read_next_result() {
+                    Ok(result) => {
+                        self.step_count += 1;
+                        if result.error.is_some() {
+                            self.failed_steps.push(result);
+                        }
+                    }
+                    Err(ReadNextResultError::Empty) => break,
+                    Err(ReadNextResultError::Finished) => {
+                        results_finished = true;
+                        break;
+                    }
+                }
+            }
+            while let Some(command) = player.read_next_logged_command() {
+                self.command_log.push(command);
+            }
+            self.display_metrics = player.display_metrics();
+
+            if results_finished && !player.is_running() {
+                self.result = Some(AirApp::describe_result(&player.get_current_status(), expansion));
+                self.player = None;
+            }
+        }
+    }
+}
+
+// the raw events of a running recording and the inputs converted from them so far
+#[derive(Default)]
+struct RecordingFeed {
+    event_count: usize,
+    // the latest events, older ones are dropped
+    events: VecDeque<InputEventInfo>,
+    inputs: Vec<InputWithTimestamp>,
+}
+
+impl RecordingFeed {
+    const MAX_EVENTS: usize = 500;
+
+    fn push(&mut self, status: StatusMessage) {
+        match status {
+            StatusMessage::RecordedInput(event) => {
+                self.event_count += 1;
+                if self.events.len() == Self::MAX_EVENTS {
+                    self.events.pop_front();
+                }
+                self.events.push_back(event);
+            }
+            StatusMessage::ConvertedInput(input) => self.inputs.push(input),
+            StatusMessage::RecordingFinished => (),
+        }
+    }
+
+    fn draw(&self, ui: &mut egui::Ui) {
+        ui.label(format!("{} events, {} inputs", self.event_count, self.inputs.len()));
+        ui.columns(2, |columns| {
+            egui::ScrollArea::vertical().id_source("recorded_events").max_height(300.0).stick_to_bottom(true).show(&mut columns[0], |ui| {
+                for event in &self.events {
+                    ui.label(RichText::new(event.to_string()).monospace());
+                }
+            });
+            egui::ScrollArea::vertical().id_source("recorded_inputs").max_height(300.0).stick_to_bottom(true).show(&mut columns[1], |ui| {
+                for input in &self.inputs {
+                    ui.label(RichText::new(input.to_string()).monospace());
+                }
+            });
+        });
+    }
 }
 
 impl Default for AirApp {
@@ -34,11 +210,40 @@ impl Default for AirApp {
         Self {
             input: Default::default(),
             record_task: Default::default(),
+            recording_feed: Default::default(),
             input_strings: Default::default(),
-            play_task: Default::default(),
+            plays: Default::default(),
+            script_header: Default::default(),
+            file_status: Default::default(),
+            play_error: Default::default(),
+            devices: Default::default(),
+            devices_task: Default::default(),
+            play_expansion: Default::default(),
+            export_task: Default::default(),
+            known_display_metrics: Vec::new(),
+            event_log: Default::default(),
+            diagnostics: Default::default(),
+            breakpoints: Default::default(),
+            selection: Default::default(),
             tap_threshold_distance : 100,
             tap_threshold_ms : 500,
             delay_ms_between_loops : 200,
+            repetitions : 0,
+            max_minutes : 0,
+            stop_at_enabled : false,
+            stop_at_hour : 6,
+            stop_at_minute : 0,
+            stop_on_error : true,
+            playback_speed : 1.0,
+            scale_swipe_durations : false,
+            spin_wait : false,
+            random_seed : 0,
+            fixed_seed : false,
+            normalize_coordinates : false,
+            script_path : "recording.air".to_string(),
+            import_path : String::new(),
+            play_devices : Vec::new(),
+            start_together : true,
         }
     }
 }
@@ -61,34 +266,650 @@ impl AirApp {
 
     fn draw_main(&mut self, ctx: &egui::Context, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
         if let Some(recorder) = &mut self.record_task {
-            match Self::handle_recording(recorder, ctx, ui, _frame) {
+            match Self::handle_recording(recorder, &mut self.recording_feed, ui, _frame) {
                 Err(_) => self.record_task = None,
                 Ok(None) => (),
-                Ok(Some(res)) => {
-                    self.input_strings = Some(InputStrings::from_inputs(&res));
-                    self.input = Some(Arc::new(res));
+                Ok(Some((script, event_log))) => {
+                    self.set_script(script);
+                    self.event_log = Some(event_log);
                     self.record_task = None;
                 }
             };
         } else {
             if ui.button("Start Recording").clicked() {
-                self.record_task = Some(InputRecorder::new(ctx, self.tap_threshold_distance, self.tap_threshold_ms));
+                self.record_task = Some(InputRecorder::new(ctx, self.tap_threshold_distance, self.tap_threshold_ms, self.normalize_coordinates));
+                self.recording_feed = RecordingFeed::default();
                 self.input = None;
+                self.event_log = None;
             }
         }
 
-        if let Some(player) = &mut self.play_task {
-            if player.is_running() {
+        for play in &mut self.plays {
+            play.update(self.play_expansion.as_ref());
+        }
+
+        self.draw_devices(ctx, ui);
+
+        if self.is_playing() {
+            let is_paused = self.players().any(|p| matches!(p.get_current_status(), InputReplayState::Paused(_)));
+            let mut command = None;
+            ui.horizontal(|ui| {
                 if ui.button("Stop Playing").clicked() {
-                    player.stop();
-                    self.play_task = None;
+                    for play in &mut self.plays {
+                        if let Some(player) = &mut play.player {
+                            player.stop();
+                        }
+                    }
+                }
+
+                // sent to all devices, so they stay at the same line
+                if is_paused {
+                    if ui.button("Resume").clicked() {
+                        command = Some(PlayerCommand::Resume);
+                    }
+                    if ui.button("Step").on_hover_text_at_pointer("Runs the next input and pauses again").clicked() {
+                        command = Some(PlayerCommand::Step);
+                    }
+                } else if ui.button("Pause").clicked() {
+                    command = Some(PlayerCommand::Pause);
+                }
+            });
+
+            if let Some(command) = command {
+                for player in self.players() {
+                    player.send_command(command.clone());
                 }
             }
         } else if let Some(input) = &self.input {
-            if ui.button("Play Recording").clicked() {
-                self.play_task = Some(InputPlayer::new(ctx, input.clone(), self.delay_ms_between_loops));
+            let line_count = input.len();
+            let mut play_lines = None;
+            let mut dry_run = false;
+            ui.horizontal(|ui| {
+                if ui.button("Play Recording").clicked() {
+                    play_lines = Some(None);
+                }
+
+                if ui.button("Dry Run")
+                    .on_hover_text_at_pointer("Goes through the script without sending anything and lists the adb commands with the time they would be sent at")
+                    .clicked()
+                {
+                    play_lines = Some(None);
+                    dry_run = true;
+                }
+
+                let has_selection = !self.selection.is_empty();
+                if ui.add_enabled(has_selection, egui::Button::new("Play Selection"))
+                    .on_hover_text_at_pointer("Plays only the selected lines, the first one without delay. Click a line to select it, shift-click to select a range and ctrl-click to add or remove a line")
+                    .clicked()
+                {
+                    play_lines = Some(Some(self.selection.clone()));
+                }
+
+                if ui.add_enabled(has_selection, egui::Button::new("Play From Selection"))
+                    .on_hover_text_at_pointer("Plays from the first selected line to the end, the first line without delay")
+                    .clicked()
+                {
+                    let start = self.selection.iter().copied().min().unwrap_or(0);
+                    play_lines = Some(Some((start..line_count).collect()));
+                }
+            });
+
+            if let Some(lines) = play_lines {
+                self.start_playing(ctx, lines, dry_run);
+            }
+        }
+
+        if let Some(error) = &self.play_error {
+            ui.label(error);
+        }
+
+        if !self.diagnostics.is_empty() {
+            let errors = self.diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+            ui.label(format!(
+                "the script has {} errors and {} warnings, they are listed below their lines",
+                errors,
+                self.diagnostics.len() - errors
+            ));
+        }
+
+        for play in &self.plays {
+            self.draw_device_play(play, ui);
+        }
+
+        ui.separator();
+        self.draw_script_file(ui);
+    }
+
+    fn is_playing(&self) -> bool {
+        self.plays.iter().any(|p| p.player.is_some())
+    }
+
+    // the players still running
+    fn players(&self) -> impl Iterator<Item = &InputPlayer> + '_ {
+        self.plays.iter().filter_map(|p| p.player.as_ref())
+    }
+
+    fn draw_devices(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        if let Some(devices_task) = &mut self.devices_task {
+            match devices_task.try_recv() {
+                Ok(devices) => {
+                    self.devices = devices.unwrap_or_default();
+                    self.devices_task = None;
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Closed) => self.devices_task = None,
+            }
+        }
+
+        let is_playing = self.is_playing();
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Devices");
+
+            if ui.add_enabled(!is_playing && self.devices_task.is_none(), egui::Button::new("Refresh devices")).clicked() {
+                let (devices_send, devices_recv) = oneshot::channel();
+                self.devices_task = Some(devices_recv);
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    _ = devices_send.send(get_connected_devices().await);
+                    ctx.request_repaint();
+                });
+            }
+
+            // selected devices stay in the list while they are not connected
+            let mut devices = self.devices.clone();
+            devices.extend(self.play_devices.iter().filter(|d| !self.devices.contains(d)).cloned());
+            for device in devices {
+                let mut selected = self.play_devices.contains(&device);
+                if ui.add_enabled(!is_playing, egui::Checkbox::new(&mut selected, &device)).changed() {
+                    if selected {
+                        self.play_devices.push(device);
+                    } else {
+                        self.play_devices.retain(|d| *d != device);
+                    }
+                }
+            }
+
+            ui.add_enabled(!is_playing && self.play_devices.len() > 1, egui::Checkbox::new(&mut self.start_together, "Start together"))
+                .on_hover_text_at_pointer("Every repetition starts when all devices finished the one before, so slow devices don't fall behind")
+            ;
+        });
+    }
+
+    // the progress of a device, or how its run ended, and its failed steps
+    fn draw_device_play(&self, play: &DevicePlay, ui: &mut egui::Ui) {
+        let expansion = self.play_expansion.as_ref();
+        ui.horizontal(|ui| {
+            ui.label(RichText::new(play.serial.as_deref().unwrap_or("device")).strong());
+
+            let status = play.player.as_ref().map(|p| p.get_current_status());
+            match status {
+                Some(InputReplayState::Repeating(Repeating { repetion, reptetion_element, lateness, .. }))
+                | Some(InputReplayState::Paused(Repeating { repetion, reptetion_element, lateness, .. })) => {
+                    let line = match reptetion_element {
+                        Some(line) => format!("line {}", Self::script_line(line, expansion)),
+                        None => "end".to_string(),
+                    };
+                    ui.label(format!("repetition {}, {}", repetion + 1, line));
+                    ui.label(format!(
+                        "late by {:.1} ms, mean {:.1} ms, max {:.1} ms",
+                        lateness.last.as_secs_f64() * 1000.0,
+                        lateness.mean().as_secs_f64() * 1000.0,
+                        lateness.max.as_secs_f64() * 1000.0,
+                    )).on_hover_text_at_pointer("How much later than scheduled the steps were sent");
+                }
+                _ => {
+                    if let Some(result) = &play.result {
+                        ui.label(result);
+                    }
+                }
+            }
+
+            if play.step_count > 0 {
+                ui.label(format!("{} steps sent, {} failed", play.step_count, play.failed_steps.len()));
+            }
+        });
+
+        // the latest failures, with the line as numbered in the main script
+        for failed in play.failed_steps.iter().rev().take(5) {
+            ui.label(format!(
+                "    line {} in repetition {}: {} (exit code {}, {} ms)",
+                Self::script_line(failed.line, expansion),
+                failed.repetition,
+                failed.error.as_deref().unwrap_or_default(),
+                failed.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "none".to_string()),
+                failed.latency.as_millis(),
+            )).on_hover_text_at_pointer(&failed.stderr);
+        }
+
+        if !play.command_log.is_empty() {
+            egui::CollapsingHeader::new(format!("{} commands", play.command_log.len()))
+                .id_source(("command_log", &play.serial))
+                .show(ui, |ui| {
+                    egui::ScrollArea::vertical().id_source(("command_log_scroll", &play.serial)).max_height(300.0).show(ui, |ui| {
+                        for command in &play.command_log {
+                            let line = Self::script_line(command.line, expansion);
+                            ui.add(egui::Label::new(RichText::new(format!("{}  (line {})", command, line)).monospace()));
+                        }
+                    });
+                });
+        }
+    }
+
+    // plays the given lines of the script or all of them, with the lines of their includes
+    fn start_playing(&mut self, ctx: &egui::Context, lines: Option<Vec<usize>>, dry_run: bool) {
+        let input = match &self.input {
+            Some(input) => input,
+            None => return,
+        };
+
+        if !self.fixed_seed {
+            self.random_seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        }
+
+        let mut expanded = match expand_includes(input, Path::new(&self.script_path)) {
+            Ok(expanded) => expanded,
+            Err(err) => {
+                self.play_error = Some(format!("can't play: {:?}", err));
+                return;
+            }
+        };
+
+        // unselected lines are blanked after expanding, so the played lines still map back to the script
+        if let Some(lines) = lines {
+            let origins = &expanded.origins;
+            select_lines(&mut expanded.lines, |idx| lines.contains(&origins[idx].chain[0].1));
+        }
+
+        // the sizes the last players asked for
+        let learned: Vec<_> = self.plays.iter().map(|p| (p.serial.clone(), p.display_metrics.clone())).collect();
+        for (serial, display_metrics) in learned {
+            self.remember_display_metrics(serial, display_metrics);
+        }
+
+        // pixel coordinates are scaled to each selected device, the only connected one is played on as recorded
+        let serials: Vec<Option<String>> = match self.play_devices.is_empty() {
+            true => vec![None],
+            false => self.play_devices.iter().cloned().map(Some).collect(),
+        };
+        let recorded_screens = match self.play_devices.is_empty() {
+            true => None,
+            false => self.script_header.as_ref().and_then(|h| h.screen_infos.clone()),
+        };
+        let barrier = match self.start_together && serials.len() > 1 {
+            true => Some(Arc::new(StartBarrier::new(serials.len()))),
+            false => None,
+        };
+
+        let lines = Arc::new(expanded.lines.clone());
+        let breakpoints = Self::expanded_breakpoints(&self.breakpoints, &expanded);
+        let mut plays = Vec::new();
+        for (idx, serial) in serials.into_iter().enumerate() {
+            let device = PlayDevice {
+                serial: serial.clone(),
+                recorded_screens: recorded_screens.clone(),
+                barrier: barrier.clone().map(|b| (b, idx)),
+                dry_run,
+                // playing asks again, the display may have been rotated or resized
+                display_metrics: match dry_run {
+                    true => self.display_metrics_of(&serial),
+                    false => Vec::new(),
+                },
+                offline: dry_run,
+            };
+            // a dry run doesn't wait, without a limit it would never end
+            let mut stop_conditions = self.stop_conditions();
+            if dry_run && stop_conditions.repetitions.is_none() {
+                stop_conditions.repetitions = Some(1);
+            }
+            match InputPlayer::new(
+                ctx,
+                lines.clone(),
+                self.delay_ms_between_loops,
+                stop_conditions,
+                self.random_seed,
+                self.playback_speed(),
+                device,
+            ) {
+                Ok(player) => {
+                    player.send_command(PlayerCommand::SetBreakpoints(breakpoints.clone()));
+                    plays.push(DevicePlay {
+                        serial,
+                        player: Some(player),
+                        result: None,
+                        step_count: 0,
+                        failed_steps: Vec::new(),
+                        command_log: Vec::new(),
+                        display_metrics: Vec::new(),
+                    });
+                }
+                // the players already started are stopped when dropped
+                Err(err) => {
+                    self.play_error = Some(format!("can't play: {:?}", err));
+                    return;
+                }
+            }
+        }
+
+        self.plays = plays;
+        self.play_expansion = Some(expanded);
+        self.play_error = None;
+    }
+
+    // breakpoints are set on the lines of the main script, a breakpoint on an include pauses before its first line
+    fn expanded_breakpoints(breakpoints: &[usize], expansion: &ExpandedScript) -> Vec<usize> {
+        breakpoints
+            .iter()
+            .filter_map(|b| expansion.origins.iter().position(|o| o.chain[0].1 == *b))
+            .collect()
+    }
+
+    fn remember_display_metrics(&mut self, serial: Option<String>, display_metrics: DisplaySizes) {
+        let idx = match self.known_display_metrics.iter().position(|(s, _)| *s == serial) {
+            Some(idx) => idx,
+            None => {
+                self.known_display_metrics.push((serial, Vec::new()));
+                self.known_display_metrics.len() - 1
             }
+        };
+        let known = &mut self.known_display_metrics[idx].1;
+        for (display_id, metrics) in display_metrics {
+            match known.iter_mut().find(|(id, _)| *id == display_id) {
+                Some((_, m)) => *m = metrics,
+                None => known.push((display_id, metrics)),
+            }
+        }
+    }
+
+    fn display_metrics_of(&self, serial: &Option<String>) -> DisplaySizes {
+        self.known_display_metrics
+            .iter()
+            .find(|(s, _)| s == serial)
+            .map(|(_, known)| known.clone())
+            .unwrap_or_default()
+    }
+
+    fn stop_conditions(&self) -> StopConditions {
+        StopConditions {
+            repetitions: Some(self.repetitions).filter(|r| *r > 0),
+            max_duration: Some(self.max_minutes).filter(|m| *m > 0).map(|m| Duration::from_secs(m as u64 * 60)),
+            stop_at: self.stop_at(),
+            continue_on_error: !self.stop_on_error,
+        }
+    }
+
+    // the next time the clock shows stop_at_hour:stop_at_minute, today or tomorrow
+    fn stop_at(&self) -> Option<SystemTime> {
+        if !self.stop_at_enabled {
+            return None;
+        }
+        let now = Local::now();
+        let mut time = now.naive_local().date().and_hms_opt(self.stop_at_hour, self.stop_at_minute, 0)?;
+        if time <= now.naive_local() {
+            time += chrono::Duration::days(1);
+        }
+        Local.from_local_datetime(&time).earliest().map(SystemTime::from)
+    }
+
+    // the line as numbered in the main script
+    fn script_line(line: usize, expansion: Option<&ExpandedScript>) -> usize {
+        expansion.and_then(|e| e.origins.get(line)).map(|o| o.chain[0].1).unwrap_or(line)
+    }
+
+    // the state a player ended in, stopping takes a moment so it may still be running
+    fn describe_result(state: &InputReplayState, expansion: Option<&ExpandedScript>) -> String {
+        match state {
+            InputReplayState::Finished { repetitions, reason } => {
+                let reason = match reason {
+                    StopReason::Stopped => "stopped",
+                    StopReason::Repetitions => "finished",
+                    StopReason::MaxDuration => "reached the maximum duration",
+                    StopReason::StopTime => "reached the stop time",
+                };
+                format!("{} after {} repetitions", reason, repetitions)
+            }
+            InputReplayState::Failed { line, message, repetitions } => {
+                format!("line {} failed after {} repetitions: {}", Self::script_line(*line, expansion), repetitions, message)
+            }
+            InputReplayState::Repeating(Repeating { repetion, .. }) | InputReplayState::Paused(Repeating { repetion, .. }) => {
+                format!("stopped after {} repetitions", repetion)
+            }
+            InputReplayState::NotStarted => "stopped".to_string(),
+        }
+    }
+
+    fn playback_speed(&self) -> PlaybackSpeed {
+        PlaybackSpeed {
+            factor: self.playback_speed,
+            scale_swipes: self.scale_swipe_durations,
+            spin_wait: self.spin_wait,
+        }
+    }
+
+    fn set_script(&mut self, script: Script) {
+        self.breakpoints.clear();
+        self.selection.clear();
+        self.input_strings = Some(InputStrings::from_inputs(&script.lines));
+        self.diagnostics = lint_script(&script.lines, script.header.screen_infos.as_ref());
+        self.input = Some(Arc::new(script.lines));
+        self.script_header = Some(script.header);
+    }
+
+    fn draw_script_file(&mut self, ui: &mut egui::Ui) {
+        let is_busy = self.record_task.is_some() || self.is_playing();
+
+        ui.horizontal(|ui| {
+            ui.label("Script file");
+            ui.text_edit_singleline(&mut self.script_path);
+
+            if ui.add_enabled(!is_busy && self.input.is_some(), egui::Button::new("Save")).clicked() {
+                self.file_status = Some(match self.save_script() {
+                    Ok(()) => format!("saved {}", self.script_path),
+                    Err(err) => format!("saving failed: {:?}", err),
+                });
+            }
+
+            if ui.add_enabled(!is_busy, egui::Button::new("Load")).clicked() {
+                self.file_status = Some(match self.load_script() {
+                    Ok(()) => format!("loaded {}", self.script_path),
+                    Err(err) => format!("loading failed: {:?}", err),
+                });
+            }
+
+            if ui.add_enabled(!is_busy && self.event_log.is_some(), egui::Button::new("Convert again"))
+                .on_hover_text_at_pointer("Converts the recorded events again with the current thresholds, changes to the script are lost")
+                .clicked()
+            {
+                self.convert_event_log();
+            }
+
+            if ui.add_enabled(!is_busy && self.input.is_some(), egui::Button::new("Absolute timing")).clicked() {
+                self.convert_timing(to_absolute_timing);
+            }
+
+            if ui.add_enabled(!is_busy && self.input.is_some(), egui::Button::new("Relative timing")).clicked() {
+                self.convert_timing(to_relative_timing);
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Import file");
+            ui.text_edit_singleline(&mut self.import_path);
+
+            if ui.add_enabled(!is_busy && !self.import_path.is_empty(), egui::Button::new("Import getevent log"))
+                .on_hover_text_at_pointer("Converts the log of the import file, the script file is set to a new file next to it")
+                .clicked()
+            {
+                self.file_status = Some(match self.import_event_log() {
+                    Ok(()) => format!("imported {} as {}", self.import_path, self.script_path),
+                    Err(err) => format!("importing failed: {:?}", err),
+                });
+            }
+        });
+
+        ui.horizontal(|ui| {
+            let can_export = !is_busy && self.input.is_some() && self.export_task.is_none();
+            ui.label("Export");
+
+            if ui.add_enabled(can_export, egui::Button::new("Shell script")).clicked() {
+                self.start_export(ui.ctx(), ExportFormat::Shell(ShellTarget::Host));
+            }
+
+            if ui.add_enabled(can_export, egui::Button::new("Device shell script")).clicked() {
+                self.start_export(ui.ctx(), ExportFormat::Shell(ShellTarget::Device));
+            }
+
+            if ui.add_enabled(can_export, egui::Button::new("Python (adb)")).clicked() {
+                self.start_export(ui.ctx(), ExportFormat::Python(PythonTarget::Subprocess));
+            }
+
+            if ui.add_enabled(can_export, egui::Button::new("Python (uiautomator2)")).clicked() {
+                self.start_export(ui.ctx(), ExportFormat::Python(PythonTarget::Uiautomator2));
+            }
+        });
+
+        if let Some(export_task) = &mut self.export_task {
+            match export_task.try_recv() {
+                Ok((status, display_metrics)) => {
+                    self.file_status = Some(status);
+                    self.export_task = None;
+                    self.remember_display_metrics(None, display_metrics);
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Closed) => self.export_task = None,
+            }
+        }
+
+        if let Some(status) = &self.file_status {
+            ui.label(status);
+        }
+    }
+
+    // the display sizes are asked from the device for normalized and dp coordinates, so exporting runs as task
+    fn start_export(&mut self, ctx: &egui::Context, format: ExportFormat) {
+        let input = match &self.input {
+            Some(input) => input,
+            None => return,
+        };
+
+        let script_path = Path::new(&self.script_path);
+        let lines = match expand_includes(input, script_path) {
+            Ok(expanded) => expanded.lines,
+            Err(err) => {
+                self.file_status = Some(format!("export failed: {:?}", err));
+                return;
+            }
+        };
+        let name = script_path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        // python files are named so pytest finds them
+        let path = match format {
+            ExportFormat::Shell(ShellTarget::Host) => script_path.with_extension("sh"),
+            ExportFormat::Shell(ShellTarget::Device) => script_path.with_extension("device.sh"),
+            ExportFormat::Python(PythonTarget::Subprocess) => script_path.with_file_name(format!("test_{}.py", name)),
+            ExportFormat::Python(PythonTarget::Uiautomator2) => script_path.with_file_name(format!("test_{}_u2.py", name)),
+        };
+
+        let (status_send, status_recv) = oneshot::channel();
+        self.export_task = Some(status_recv);
+
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let mut display_metrics = Vec::new();
+            for display_id in used_displays(&lines) {
+                if let Some(metrics) = get_display_metrics(None, display_id).await {
+                    display_metrics.push((display_id, metrics));
+                }
+            }
+
+            let res = File::create(&path)
+                .map_err(|_| ExportError::Io)
+                .and_then(|file| export_script(&lines, format, &name, &display_metrics, &mut BufWriter::new(file)));
+
+            let status = match res {
+                Ok(()) => format!("exported {}", path.display()),
+                Err(err) => format!("export failed: {:?}", err),
+            };
+            _ = status_send.send((status, display_metrics));
+            ctx.request_repaint();
+        });
+    }
+
+    fn convert_timing(&mut self, convert: fn(&mut [ScriptLine])) {
+        if let Some(input) = &mut self.input {
+            let lines = Arc::make_mut(input);
+            convert(lines);
+            self.input_strings = Some(InputStrings::from_inputs(lines));
+            self.diagnostics = lint_script(lines, self.script_header.as_ref().and_then(|h| h.screen_infos.as_ref()));
+        }
+    }
+
+    // ".txt" files are written in the plain line format, everything else as versioned document
+    fn save_script(&self) -> Result<(), ScriptError> {
+        let script = Script {
+            header: self.script_header.clone().unwrap_or_default(),
+            lines: self.input.as_deref().cloned().unwrap_or_default(),
+        };
+
+        let file = File::create(&self.script_path).map_err(|_| ScriptError::Io)?;
+        let mut writer = BufWriter::new(file);
+
+        if self.script_path.ends_with(".txt") {
+            serialize_inputs(&script.lines, &mut writer);
+        } else {
+            serialize_script(&script, &mut writer)?;
+        }
+
+        if let Some(event_log) = &self.event_log {
+            save_event_log(&event_log_path(Path::new(&self.script_path)), event_log)?;
         }
+        Ok(())
+    }
+
+    fn load_script(&mut self) -> Result<(), ScriptError> {
+        let path = Path::new(&self.script_path);
+        let script = load_script_file(path)?;
+        // reports broken includes when loading instead of when playing
+        expand_includes(&script.lines, path)?;
+        // scripts written by hand or exported from other tools have no event log
+        let event_log = load_event_log(&event_log_path(path)).ok();
+        self.set_script(script);
+        self.event_log = event_log;
+        Ok(())
+    }
+
+    // converts the event log again with the current thresholds, edits of the script are lost
+    fn convert_event_log(&mut self) {
+        let event_log = match self.event_log.take() {
+            Some(event_log) => event_log,
+            None => return,
+        };
+
+        let conversion = ConversionSettings {
+            tap_threshold_distance: self.tap_threshold_distance,
+            tap_threshold_ms: self.tap_threshold_ms,
+            normalize_coordinates: self.normalize_coordinates,
+        };
+        let mut header = self.script_header.clone().unwrap_or_default();
+        let screen_infos = header.screen_infos.clone().unwrap_or_default();
+        let inputs = event_log.convert(conversion, &screen_infos);
+        header.conversion = Some(conversion);
+
+        self.set_script(Script {
+            header,
+            lines: inputs.into_iter().map(ScriptLine::Input).collect(),
+        });
+        self.event_log = Some(event_log);
+    }
+
+    // `getevent -t -l` output, for example from a bug report. The screen is not known, so coordinates stay pixels
+    fn import_event_log(&mut self) -> Result<(), ScriptError> {
+        let path = Path::new(&self.import_path);
+        let event_log = load_event_log(path)?;
+        // saving writes the script and "<name>.imported.getevent", neither can be the imported file
+        self.script_path = path.with_extension("imported.air").to_string_lossy().to_string();
+        self.script_header = None;
+        self.event_log = Some(event_log);
+        self.convert_event_log();
+        Ok(())
     }
 
     fn draw_settings(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
@@ -106,29 +927,147 @@ impl AirApp {
             ui.add(egui::Slider::new(&mut self.delay_ms_between_loops, 0..=10000).text("MS between loops"))
                 .on_hover_text_at_pointer("The app waits this many milliconds between each repetition of the recorded inputs")
             ;
+
+            ui.add(egui::Slider::new(&mut self.repetitions, 0..=1000).logarithmic(true).text("Repetitions"))
+                .on_hover_text_at_pointer("Stops after playing the inputs this many times, 0 repeats until stopped")
+            ;
+
+            ui.add(egui::Slider::new(&mut self.max_minutes, 0..=1440).logarithmic(true).text("Max minutes"))
+                .on_hover_text_at_pointer("Stops after playing this long, even in the middle of the inputs. 0 has no limit")
+            ;
+
+            ui.checkbox(&mut self.stop_on_error, "Stop on failing step")
+                .on_hover_text_at_pointer("Stops playing when adb reports an error for a step, for example because the device is offline. Otherwise failing steps are only listed")
+            ;
+
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.stop_at_enabled, "Stop at")
+                    .on_hover_text_at_pointer("Stops when the clock shows this time, today or tomorrow. For runs over night")
+                ;
+                ui.add_enabled(self.stop_at_enabled, egui::DragValue::new(&mut self.stop_at_hour).clamp_range(0..=23));
+                ui.label(":");
+                ui.add_enabled(self.stop_at_enabled, egui::DragValue::new(&mut self.stop_at_minute).clamp_range(0..=59));
+            });
+
+            // the speed can be changed while playing
+            let speed_changed = ui.add(egui::Slider::new(&mut self.playback_speed, 0.25..=10.0).logarithmic(true).text("Playback speed"))
+                .on_hover_text_at_pointer("Plays faster or slower than recorded, 2 halves the delays between the inputs")
+                .changed()
+            ;
+
+            let scale_changed = ui.checkbox(&mut self.scale_swipe_durations, "Scale swipe durations")
+                .on_hover_text_at_pointer("Swipes are faster or slower with the playback speed too, otherwise they take the recorded time")
+                .changed()
+            ;
+
+            let spin_changed = ui.checkbox(&mut self.spin_wait, "Precise timing")
+                .on_hover_text_at_pointer("Waits actively for the last milliseconds before each input, so they are sent closer to their time. Keeps a CPU core busy")
+                .changed()
+            ;
+
+            if speed_changed || scale_changed || spin_changed {
+                for player in self.players() {
+                    player.set_speed(self.playback_speed());
+                }
+            }
+
+            ui.horizontal(|ui| {
+                ui.add_enabled(self.fixed_seed, egui::DragValue::new(&mut self.random_seed));
+                ui.checkbox(&mut self.fixed_seed, "Fixed random seed")
+                    .on_hover_text_at_pointer("Play with the same seed again, so rand() gives the same values and a failing run can be reproduced")
+                ;
+            });
+
+            ui.checkbox(&mut self.normalize_coordinates, "Record normalized coordinates")
+                .on_hover_text_at_pointer("Record positions as fraction of the screen size, so the recording can be played on devices with a different resolution")
+            ;
         });       
     }
 
-    fn draw_input_strings(input_strings : &InputStrings, replay_state : Option<InputReplayState>, _ctx: &egui::Context, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+    // returns true if a breakpoint was toggled
+    fn draw_input_strings(input_strings : &InputStrings, replay_state : Option<&InputReplayState>, expansion : Option<&ExpandedScript>, breakpoints : &mut Vec<usize>, selection : &mut Vec<usize>, diagnostics : &[Diagnostic], ui: &mut egui::Ui) -> bool {
+        let mut breakpoints_changed = false;
+        // played lines are counted in the expanded script, the GUI shows the main script
+        let origin = |idx: usize| expansion.and_then(|e| e.origins.get(idx));
+        let current = replay_state.and_then(
+            |s| match s {
+                InputReplayState::Repeating(Repeating { reptetion_element: Some(idx), .. })
+                | InputReplayState::Paused(Repeating { reptetion_element: Some(idx), .. }) => origin(*idx),
+                _ => None,
+            }
+        );
+
         for (i, s) in input_strings.0.iter().enumerate()
         {
-            let is_current = replay_state.map(
+            let is_current = current.map(|o| o.chain[0].1 == i).unwrap_or(false);
+
+            // running repeat blocks show which iteration they are in
+            let loop_position = replay_state.and_then(
                 |s| match s {
-                    InputReplayState::Repeating(Repeating { repetion : _, reptetion_element: Some(idx)  }) => idx == i,
-                    _ => false,
+                    InputReplayState::Repeating(Repeating { loops, .. }) | InputReplayState::Paused(Repeating { loops, .. }) => loops.iter().find(
+                        |l| origin(l.line).map(|o| o.chain.len() == 1 && o.chain[0].1 == i).unwrap_or(false)
+                    ),
+                    _ => None,
+                }
+            );
+            let text = match loop_position {
+                Some(l) => format!("{}    ({}/{})", s, l.iteration + 1, l.count),
+                None => s.clone(),
+            };
+
+            ui.horizontal(|ui| {
+                let is_breakpoint = breakpoints.contains(&i);
+                if ui.selectable_label(is_breakpoint, "●").on_hover_text_at_pointer("Pause before this line").clicked() {
+                    if is_breakpoint {
+                        breakpoints.retain(|b| *b != i);
+                    } else {
+                        breakpoints.push(i);
+                    }
+                    breakpoints_changed = true;
                 }
-            ).unwrap_or(false);
+
+                let is_selected = selection.contains(&i);
+                let response = if is_current {
+                    let response = ui.selectable_label(is_selected, RichText::new(&text).strong().monospace());
+                    response.scroll_to_me(None);
+                    response
+                } else {
+                    ui.selectable_label(is_selected, RichText::new(&text).monospace())
+                };
+
+                if response.clicked() {
+                    let modifiers = ui.input().modifiers;
+                    Self::select_line(selection, i, modifiers.shift, modifiers.command);
+                }
+            });
+
+            for diagnostic in diagnostics.iter().filter(|d| d.line == i) {
+                let (symbol, color) = match diagnostic.severity {
+                    Severity::Warning => ("⚠", ui.visuals().warn_fg_color),
+                    Severity::Error => ("⛔", ui.visuals().error_fg_color),
+                };
+                ui.label(RichText::new(format!("      {} {}", symbol, diagnostic)).color(color).monospace());
+            }
 
             if is_current {
-                ui.add(egui::Label::new(RichText::new(s).strong().monospace())).scroll_to_me(None); 
-            } else {
-                ui.add(egui::Label::new(RichText::new(s).monospace())); 
+
+                // the place inside included scripts, "login.air:  5 > menu.air:  2"
+                if let (Some(origin), Some(expansion)) = (current, expansion) {
+                    if origin.chain.len() > 1 {
+                        let nested = origin.chain[1..]
+                            .iter()
+                            .map(|(file, line)| format!("{}:{:3}", expansion.files[*file].display(), line))
+                            .collect::<Vec<_>>()
+                            .join(" > ");
+                        ui.add(egui::Label::new(RichText::new(format!("    in {}", nested)).strong().monospace()));
+                    }
+                }
             }
         }
 
         let is_end = replay_state.map(
             |s| match s {
-                InputReplayState::Repeating(Repeating { repetion : _, reptetion_element: None  }) => true,
+                InputReplayState::Repeating(Repeating { reptetion_element: None, .. }) => true,
                 _ => false,
             }
         ).unwrap_or(false);
@@ -138,18 +1077,40 @@ impl AirApp {
         } else {
             ui.add(egui::Label::new(RichText::new("END OF INPUTS").monospace())); 
         }
+
+        breakpoints_changed
+    }
+
+    // click selects only the line, shift-click the range from the line clicked before and ctrl-click adds or removes it
+    fn select_line(selection: &mut Vec<usize>, line: usize, range: bool, toggle: bool) {
+        match (selection.last().copied(), range, toggle) {
+            (Some(anchor), true, _) => {
+                let (first, last) = (anchor.min(line), anchor.max(line));
+                selection.retain(|s| *s != anchor && (*s < first || *s > last));
+                selection.extend((first..=last).filter(|s| *s != anchor));
+                // stays the anchor of the next shift-click
+                selection.push(anchor);
+            }
+            (_, _, true) if selection.contains(&line) => selection.retain(|s| *s != line),
+            (_, _, true) => selection.push(line),
+            _ if selection == &[line] => selection.clear(),
+            _ => {
+                selection.clear();
+                selection.push(line);
+            }
+        }
     }
 
     // handles recording, if finsihed return it's result, return error if something bad happend and the recordder should be destroyed
     fn handle_recording(
         recorder: &mut InputRecorder,
-        _ctx: &egui::Context,
+        feed: &mut RecordingFeed,
         ui: &mut egui::Ui,
         _frame: &mut eframe::Frame,
-    ) -> Result<Option<Vec<InputWithTimestamp>>, ()> {
+    ) -> Result<Option<(Script, EventLog)>, ()> {
         loop {
             match recorder.read_next_status() {
-                Ok(_) => (), // TODO DISPLAY
+                Ok(status) => feed.push(status),
                 Err(ReadNextStatusError::Empty) => break,
                 Err(ReadNextStatusError::Finished) => break,
             }
@@ -159,6 +1120,7 @@ impl AirApp {
             if ui.button("Stop Recording").clicked() {
                 recorder.stop();
             }
+            feed.draw(ui);
             Ok(None)
         } else {
             ui.label("Stopping Recording ...");
@@ -225,11 +1187,19 @@ impl eframe::App for AirApp {
 
         if let Some(input_strings) = &self.input_strings
         {
-            let replay_status = self.play_task.as_ref().map(|t|t.get_current_status());
+            // the list shows where the first device is
+            let replay_status = self.players().next().map(|t|t.get_current_status());
 
             egui::TopBottomPanel::bottom("bottom_panel").resizable(true).show(ctx, |ui| {
                 egui::ScrollArea::vertical().show(ui, |ui|{
-                Self::draw_input_strings(input_strings, replay_status, ctx, ui, _frame);
+                    let breakpoints_changed = Self::draw_input_strings(input_strings, replay_status.as_ref(), self.play_expansion.as_ref(), &mut self.breakpoints, &mut self.selection, &self.diagnostics, ui);
+                    if breakpoints_changed {
+                        if let Some(expansion) = &self.play_expansion {
+                            for player in self.plays.iter().filter_map(|p| p.player.as_ref()) {
+                                player.send_command(PlayerCommand::SetBreakpoints(Self::expanded_breakpoints(&self.breakpoints, expansion)));
+                            }
+                        }
+                    }
                 });
             });
         }        
@@ -0,0 +1,132 @@
+use crate::input::ScriptLine;
+
+#[derive(Clone, Debug)]
+pub enum ControlFlowError {
+    // line index of a "}" without "repeat"
+    UnmatchedRepeatEnd(usize),
+    // line index of a "repeat" without "}"
+    UnclosedRepeat(usize),
+    UnknownLabel { line: usize, name: String },
+    DuplicateLabel { line: usize, name: String },
+    // a goto may only jump to labels in the same or an enclosing repeat block
+    JumpIntoRepeat { line: usize, name: String },
+}
+
+// jump targets of a script, resolved once before playing
+pub struct ControlFlow {
+    // for "repeat" the index of the matching "}" and the other way round
+    block_partner: Vec<Option<usize>>,
+    // for "goto" the index of its label
+    goto_target: Vec<Option<usize>>,
+    // the innermost "repeat" each line is in
+    enclosing_repeat: Vec<Option<usize>>,
+}
+
+impl ControlFlow {
+    pub fn new(lines: &[ScriptLine]) -> Result<Self, ControlFlowError> {
+        let mut block_partner = vec![None; lines.len()];
+        let mut enclosing_repeat = vec![None; lines.len()];
+        let mut open_repeats = Vec::new();
+        let mut labels = Vec::new();
+
+        for (idx, line) in lines.iter().enumerate() {
+            enclosing_repeat[idx] = open_repeats.last().copied();
+            match line {
+                ScriptLine::RepeatStart(_) => open_repeats.push(idx),
+                ScriptLine::RepeatEnd => {
+                    let start = open_repeats.pop().ok_or(ControlFlowError::UnmatchedRepeatEnd(idx))?;
+                    block_partner[start] = Some(idx);
+                    block_partner[idx] = Some(start);
+                    // the "}" belongs to the block it closes
+                    enclosing_repeat[idx] = Some(start);
+                }
+                ScriptLine::Label(name) => {
+                    if labels.iter().any(|(_, n)| n == name) {
+                        return Err(ControlFlowError::DuplicateLabel { line: idx, name: name.clone() });
+                    }
+                    labels.push((idx, name.clone()));
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(start) = open_repeats.first() {
+            return Err(ControlFlowError::UnclosedRepeat(*start));
+        }
+
+        let mut flow = Self {
+            block_partner,
+            goto_target: vec![None; lines.len()],
+            enclosing_repeat,
+        };
+
+        for (idx, line) in lines.iter().enumerate() {
+            if let ScriptLine::Goto(name) = line {
+                let target = labels
+                    .iter()
+                    .find(|(_, n)| n == name)
+                    .map(|(target, _)| *target)
+                    .ok_or_else(|| ControlFlowError::UnknownLabel { line: idx, name: name.clone() })?;
+
+                let target_block = flow.enclosing_repeat[target];
+                if target_block.is_some() && !flow.is_inside(idx, target_block) {
+                    return Err(ControlFlowError::JumpIntoRepeat { line: idx, name: name.clone() });
+                }
+                flow.goto_target[idx] = Some(target);
+            }
+        }
+
+        Ok(flow)
+    }
+
+    pub fn block_partner(&self, line: usize) -> Option<usize> {
+        self.block_partner[line]
+    }
+
+    pub fn goto_target(&self, line: usize) -> Option<usize> {
+        self.goto_target[line]
+    }
+
+    // true if the line is inside the repeat block, None is the whole script
+    pub fn is_inside(&self, line: usize, repeat: Option<usize>) -> bool {
+        let mut block = self.enclosing_repeat[line];
+        loop {
+            if block == repeat {
+                return true;
+            }
+            match block {
+                Some(b) => block = self.enclosing_repeat[b],
+                None => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(text: &str) -> Result<ControlFlow, ControlFlowError> {
+        let lines: Vec<ScriptLine> = text.lines().map(|line| line.parse().unwrap()).collect();
+        ControlFlow::new(&lines)
+    }
+
+    #[test]
+    fn blocks_and_jumps() {
+        let flow = flow("label start\nrepeat 2 {\nrepeat 3 {\ngoto start\n}\n}").ok().unwrap();
+        assert_eq!(flow.block_partner(1), Some(5));
+        assert_eq!(flow.block_partner(4), Some(2));
+        assert_eq!(flow.goto_target(3), Some(0));
+        assert!(flow.is_inside(3, Some(1)) && flow.is_inside(3, None));
+        assert!(!flow.is_inside(0, Some(1)));
+    }
+
+    #[test]
+    fn broken_blocks_and_labels() {
+        assert!(matches!(flow("}"), Err(ControlFlowError::UnmatchedRepeatEnd(0))));
+        assert!(matches!(flow("repeat 2 {\nrepeat 2 {\n}"), Err(ControlFlowError::UnclosedRepeat(0))));
+        assert!(matches!(flow("goto nowhere"), Err(ControlFlowError::UnknownLabel { line: 0, .. })));
+        assert!(matches!(flow("label a\nlabel a"), Err(ControlFlowError::DuplicateLabel { line: 1, .. })));
+        assert!(matches!(flow("goto a\nrepeat 2 {\nlabel a\n}"), Err(ControlFlowError::JumpIntoRepeat { line: 0, .. })));
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    device_entry::DeviceEntry,
+    input::{convert_events_to_input, normalize_inputs, InputWithTimestamp},
+    input_event::InputEventInfo,
+    input_event_parser::{DeviceWithEvent, ParsedGetEventOutput},
+    input_event_recorder::ScreenInfos,
+    script::{ConversionSettings, ScriptError},
+};
+
+// the unconverted output of `getevent -t -l`, kept so a recording can be converted again with other thresholds.
+// Logs from bug reports have the same format and are imported as they are
+#[derive(Clone, Default)]
+pub struct EventLog {
+    pub text: String,
+}
+
+impl EventLog {
+    pub fn parse(&self) -> (Vec<DeviceEntry>, Vec<InputEventInfo>) {
+        let mut parser = EventLogParser::default();
+        for line in self.text.lines() {
+            parser.push_line(line);
+        }
+        (parser.devices, parser.inputs)
+    }
+
+    pub fn convert(&self, conversion: ConversionSettings, screen_infos: &ScreenInfos) -> Vec<InputWithTimestamp> {
+        let (devices, events) = self.parse();
+        let mut inputs = convert_events_to_input(
+            &events,
+            conversion.tap_threshold_distance,
+            conversion.tap_threshold_ms,
+            screen_infos,
+            &devices,
+        );
+        if conversion.normalize_coordinates {
+            normalize_inputs(&mut inputs, screen_infos);
+        }
+        inputs
+    }
+}
+
+// collects devices and events line by line, while recording and when reading a saved log
+#[derive(Default)]
+pub struct EventLogParser {
+    last_device_with_event: Option<DeviceWithEvent>,
+    pub devices: Vec<DeviceEntry>,
+    pub inputs: Vec<InputEventInfo>,
+}
+
+impl EventLogParser {
+    // returns the event of the line, lines which are no event or can't be parsed are skipped
+    pub fn push_line(&mut self, line: &str) -> Option<InputEventInfo> {
+        match ParsedGetEventOutput::try_from_str(line).ok().flatten()? {
+            ParsedGetEventOutput::AddDevice(dwe) => self.last_device_with_event = Some(dwe),
+            ParsedGetEventOutput::Name(name) => {
+                if let Some(dwe) = self.last_device_with_event.take() {
+                    self.devices.push(DeviceEntry {
+                        device_nr: dwe.device_nr,
+                        event_nr: dwe.event_nr,
+                        name: name.to_string(),
+                    });
+                } else {
+                    eprintln!("warning: igorning name no dwe present");
+                }
+            }
+            ParsedGetEventOutput::Input(input) => {
+                self.inputs.push(input);
+                return Some(input);
+            }
+        }
+        None
+    }
+}
+
+// the raw log is saved next to the script, "recording.air" -> "recording.getevent"
+pub fn event_log_path(script_path: &Path) -> PathBuf {
+    script_path.with_extension("getevent")
+}
+
+pub fn save_event_log(path: &Path, log: &EventLog) -> Result<(), ScriptError> {
+    fs::write(path, &log.text).map_err(|_| ScriptError::Io)
+}
+
+pub fn load_event_log(path: &Path) -> Result<EventLog, ScriptError> {
+    let text = fs::read_to_string(path).map_err(|_| ScriptError::Io)?;
+    Ok(EventLog { text })
+}
@@ -0,0 +1,375 @@
+use std::io::Write;
+
+use crate::input::{resolve_timeline, DisplayMetrics, Input, InputWithTimestamp, Key, ScriptLine, Stroke, Swipe};
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ShellTarget {
+    // runs on the computer, every step is an `adb shell` call
+    Host,
+    // runs on the device with `adb shell sh`, no adb round trip per step
+    Device,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum PythonTarget {
+    // every step is a `subprocess` call of adb
+    Subprocess,
+    // steps use the uiautomator2 device, `d.click`, `d.swipe`, ...
+    Uiautomator2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ExportFormat {
+    Shell(ShellTarget),
+    Python(PythonTarget),
+}
+
+#[derive(Clone, Debug)]
+pub enum ExportError {
+    Io,
+    // the line can't be expressed in the exported format, like goto or expressions
+    Unsupported(usize),
+    // the line has normalized or dp coordinates but the size of its display is not known
+    NeedsDisplayMetrics(usize),
+}
+
+// the displays the steps of the lines are sent to, None is the default display
+pub fn used_displays(lines: &[ScriptLine]) -> Vec<Option<i32>> {
+    let mut displays = Vec::new();
+    for input in lines.iter().filter_map(ScriptLine::as_input) {
+        if input.input.needs_display_metrics() && !displays.contains(&input.display_id) {
+            displays.push(input.display_id);
+        }
+    }
+    displays
+}
+
+// name is the script file name without extension, used for the python test function and the usage line of shell scripts
+pub fn export_script<T: Write>(
+    lines: &[ScriptLine],
+    format: ExportFormat,
+    name: &str,
+    display_metrics: &[(Option<i32>, DisplayMetrics)],
+    writer: &mut T,
+) -> Result<(), ExportError> {
+    match format {
+        ExportFormat::Shell(target) => export_shell_script(lines, target, name, display_metrics, writer),
+        ExportFormat::Python(target) => export_python_test(lines, target, name, display_metrics, writer),
+    }
+}
+
+// writes a posix shell script that replays the lines with their timing. Includes have to be expanded before
+pub fn export_shell_script<T: Write>(
+    lines: &[ScriptLine],
+    target: ShellTarget,
+    name: &str,
+    display_metrics: &[(Option<i32>, DisplayMetrics)],
+    writer: &mut T,
+) -> Result<(), ExportError> {
+    // the names the app writes the scripts with, see start_export
+    let file_name = match target {
+        ShellTarget::Host => format!("{}.sh", name),
+        ShellTarget::Device => format!("{}.device.sh", name),
+    };
+    let file_name = match file_name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) {
+        true => file_name,
+        false => quote(&file_name),
+    };
+
+    let mut script = String::new();
+    script += "#!/bin/sh\n";
+    match target {
+        ShellTarget::Host => script += &format!("# replay with: sh {}\n", file_name),
+        ShellTarget::Device => script += &format!("# replay with: adb push {0} /data/local/tmp/ && adb shell sh /data/local/tmp/{0}\n", file_name),
+    }
+    // a failing shell step stops the replay, like in the player
+    script += "set -e\n";
+
+    let timeline = resolve_timeline(lines);
+    let mut depth = 0;
+
+    for (idx, line) in lines.iter().enumerate() {
+        let indent = "    ".repeat(depth);
+
+        let diff = step_delay(&timeline, idx);
+        if diff > 0 {
+            script += &format!("{}sleep {}.{:03}\n", indent, diff / 1000, diff % 1000);
+        }
+
+        let input = match line {
+            ScriptLine::Input(input) => input,
+            ScriptLine::Comment(text) => {
+                script += &format!("{}#{}\n", indent, text);
+                continue;
+            }
+            ScriptLine::Section(name) => {
+                script += &format!("{}## {}\n", indent, name);
+                continue;
+            }
+            ScriptLine::Blank | ScriptLine::Label(_) => continue,
+            ScriptLine::RepeatStart(count) => {
+                script += &format!("{}i{}=0\n", indent, depth);
+                script += &format!("{}while [ $i{} -lt {} ]; do\n", indent, depth, count);
+                depth += 1;
+                continue;
+            }
+            ScriptLine::RepeatEnd => {
+                depth = depth.checked_sub(1).ok_or(ExportError::Unsupported(idx))?;
+                let indent = "    ".repeat(depth);
+                script += &format!("{}    i{}=$((i{} + 1))\n", indent, depth, depth);
+                script += &format!("{}done\n", indent);
+                continue;
+            }
+            ScriptLine::Goto(_) | ScriptLine::Template(_) | ScriptLine::Set { .. } | ScriptLine::Include(_) => {
+                return Err(ExportError::Unsupported(idx));
+            }
+        };
+
+        if let Input::Wait(_) = input.input {
+            continue;
+        }
+        let send_input = to_pixels(input, display_metrics, idx)?;
+
+        // shell steps are waited for like in the player, inputs run in the background so a long swipe doesn't delay the following steps
+        let (command, background) = match send_input.shell_command(input.display_id) {
+            Some(command) => (command, false),
+            None => (format!("input {}", input.input_args(&send_input).join(" ")), true),
+        };
+
+        let command = match target {
+            ShellTarget::Host => format!("adb shell {}", quote(&command)),
+            ShellTarget::Device => command,
+        };
+        script += &format!("{}{}{}\n", indent, command, if background { " &" } else { "" });
+    }
+
+    if let Some(Input::Wait(ms)) = lines.last().and_then(ScriptLine::as_input).map(|i| &i.input) {
+        script += &format!("sleep {}.{:03}\n", ms / 1000, ms % 1000);
+    }
+    // the last inputs may still run
+    script += "wait\n";
+
+    writer.write_all(script.as_bytes()).map_err(|_| ExportError::Io)
+}
+
+// every line waits for its own delay after the line before it, so repeated blocks keep their timing
+fn step_delay(timeline: &[u32], idx: usize) -> u32 {
+    match idx {
+        0 => timeline[0],
+        _ => timeline[idx].saturating_sub(timeline[idx - 1]),
+    }
+}
+
+fn to_pixels(input: &InputWithTimestamp, display_metrics: &[(Option<i32>, DisplayMetrics)], idx: usize) -> Result<Input, ExportError> {
+    let mut send_input = input.input.clone();
+    if send_input.needs_display_metrics() {
+        let metrics = display_metrics
+            .iter()
+            .find(|(id, _)| *id == input.display_id)
+            .map(|(_, metrics)| *metrics)
+            .ok_or(ExportError::NeedsDisplayMetrics(idx))?;
+        send_input.to_pixels(metrics);
+    }
+    Ok(send_input)
+}
+
+// single quotes keep everything as written, a quote inside is closed, escaped and opened again
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// writes the lines as python test function, meant as starting point of a maintained test, so steps are written one
+// after the other and each waits for the previous one to finish. Includes have to be expanded before
+pub fn export_python_test<T: Write>(
+    lines: &[ScriptLine],
+    target: PythonTarget,
+    test_name: &str,
+    display_metrics: &[(Option<i32>, DisplayMetrics)],
+    writer: &mut T,
+) -> Result<(), ExportError> {
+    let mut code = String::new();
+    match target {
+        PythonTarget::Subprocess => {
+            code += "import subprocess\nimport time\n\n\n";
+            code += "def adb_shell(command):\n";
+            code += "    subprocess.run([\"adb\", \"shell\", command], check=True)\n\n\n";
+        }
+        PythonTarget::Uiautomator2 => {
+            code += "import time\n\nimport uiautomator2 as u2\n\n\n";
+        }
+    }
+
+    code += &format!("def test_{}():\n", python_name(test_name));
+    if target == PythonTarget::Uiautomator2 {
+        code += "    d = u2.connect()\n";
+    }
+
+    let timeline = resolve_timeline(lines);
+    let mut depth = 1;
+    // python needs a statement in every block
+    let mut block_is_empty = target == PythonTarget::Subprocess;
+    // the steps block until they are done, the time a swipe takes is already waited. What is left of it after
+    // the delays of the following steps makes them late, it is taken from their sleeps
+    let mut running_ms: u32 = 0;
+
+    for (idx, line) in lines.iter().enumerate() {
+        let indent = "    ".repeat(depth);
+
+        let delay = step_delay(&timeline, idx);
+        let diff = delay.saturating_sub(running_ms);
+        running_ms = running_ms.saturating_sub(delay);
+        if diff > 0 {
+            code += &format!("{}time.sleep({}.{:03})\n", indent, diff / 1000, diff % 1000);
+            block_is_empty = false;
+        }
+
+        let input = match line {
+            ScriptLine::Input(input) => input,
+            ScriptLine::Comment(text) => {
+                code += &format!("{}#{}\n", indent, text);
+                continue;
+            }
+            ScriptLine::Section(name) => {
+                code += &format!("{}# {}\n", indent, name);
+                continue;
+            }
+            ScriptLine::Blank | ScriptLine::Label(_) => continue,
+            ScriptLine::RepeatStart(count) => {
+                code += &format!("{}for _ in range({}):\n", indent, count);
+                depth += 1;
+                block_is_empty = true;
+                // every run of the body starts with the same sleeps
+                running_ms = 0;
+                continue;
+            }
+            ScriptLine::RepeatEnd => {
+                if block_is_empty {
+                    code += &format!("{}pass\n", indent);
+                }
+                depth = depth.checked_sub(1).filter(|d| *d > 0).ok_or(ExportError::Unsupported(idx))?;
+                block_is_empty = false;
+                continue;
+            }
+            ScriptLine::Goto(_) | ScriptLine::Template(_) | ScriptLine::Set { .. } | ScriptLine::Include(_) => {
+                return Err(ExportError::Unsupported(idx));
+            }
+        };
+
+        if let Input::Wait(_) = input.input {
+            continue;
+        }
+        let send_input = to_pixels(input, display_metrics, idx)?;
+
+        if let Input::Swipe(s) | Input::Stroke(Stroke { swipe: s, .. }) = &send_input {
+            running_ms = running_ms.saturating_add(s.milliseconds);
+        }
+
+        let statement = match target {
+            PythonTarget::Subprocess => python_adb_shell(input, &send_input),
+            PythonTarget::Uiautomator2 => python_uiautomator2(input, &send_input),
+        };
+        code += &format!("{}{}\n", indent, statement);
+        block_is_empty = false;
+    }
+
+    if let Some(Input::Wait(ms)) = lines.last().and_then(ScriptLine::as_input).map(|i| &i.input) {
+        code += &format!("    time.sleep({}.{:03})\n", ms / 1000, ms % 1000);
+    } else if block_is_empty {
+        code += "    pass\n";
+    }
+
+    writer.write_all(code.as_bytes()).map_err(|_| ExportError::Io)
+}
+
+fn python_adb_shell(input: &InputWithTimestamp, send_input: &Input) -> String {
+    let command = send_input
+        .shell_command(input.display_id)
+        .unwrap_or_else(|| format!("input {}", input.input_args(send_input).join(" ")));
+    format!("adb_shell({})", python_str(&command))
+}
+
+// inputs uiautomator2 has no call for, or with a source or display, are sent with `input` through d.shell
+fn python_uiautomator2(input: &InputWithTimestamp, send_input: &Input) -> String {
+    let has_options = input.source.is_some() || input.display_id.is_some();
+    match send_input {
+        Input::Tap(t) if !has_options => format!("d.click({}, {})", t.x, t.y),
+        Input::Swipe(Swipe { x, y, milliseconds }) if !has_options => format!(
+            "d.swipe({}, {}, {}, {}, duration={}.{:03})",
+            x[0], y[0], x[1], y[1], milliseconds / 1000, milliseconds % 1000
+        ),
+        // d.press only knows these names, other key codes go through `input keyevent`
+        Input::Key(key @ (Key::Power | Key::Back | Key::Home | Key::Menu)) if !has_options => {
+            // "KEYCODE_BACK" -> "back"
+            let name = key.to_string().trim_start_matches("KEYCODE_").to_lowercase();
+            format!("d.press({})", python_str(&name))
+        }
+        Input::Launch(component) if input.display_id.is_none() => match component.split_once('/') {
+            Some((package, activity)) => format!("d.app_start({}, {})", python_str(package), python_str(activity)),
+            None => format!("d.app_start({})", python_str(component)),
+        },
+        Input::ForceStop(package) => format!("d.app_stop({})", python_str(package)),
+        Input::ClearData(package) => format!("d.app_clear({})", python_str(package)),
+        Input::OpenUri(uri) if input.display_id.is_none() => format!("d.open_url({})", python_str(uri)),
+        _ => {
+            let command = send_input
+                .shell_command(input.display_id)
+                .unwrap_or_else(|| format!("input {}", input.input_args(send_input).join(" ")));
+            format!("assert d.shell({}).exit_code == 0", python_str(&command))
+        }
+    }
+}
+
+fn python_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// "my recording-2" -> "my_recording_2"
+fn python_name(s: &str) -> String {
+    let name: String = s
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    match name.is_empty() {
+        true => "recording".to_string(),
+        false => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(text: &str, format: ExportFormat) -> String {
+        let lines: Vec<ScriptLine> = text.lines().map(|line| line.parse().unwrap()).collect();
+        let mut out = Vec::new();
+        export_script(&lines, format, "replay", &[], &mut out).ok().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn python_sleeps_take_the_overrun_of_a_swipe() {
+        let code = export(
+            "0 swipe 1 1 5 5 1000\n+300ms tap 1 1\n+300ms tap 2 2\n+1000ms tap 3 3",
+            ExportFormat::Python(PythonTarget::Subprocess),
+        );
+        // 1000ms of swipe cover both 300ms delays and 400ms of the last one
+        let sleeps: Vec<&str> = code.lines().filter(|l| l.contains("time.sleep")).map(str::trim).collect();
+        assert_eq!(sleeps, vec!["time.sleep(0.600)"]);
+    }
+
+    #[test]
+    fn python_repeat_body_starts_without_overrun() {
+        let code = export(
+            "0 swipe 1 1 5 5 1000\nrepeat 2 {\n+300ms tap 1 1\n}",
+            ExportFormat::Python(PythonTarget::Subprocess),
+        );
+        assert!(code.contains("    for _ in range(2):\n        time.sleep(0.300)\n"));
+    }
+
+    #[test]
+    fn shell_usage_names_the_file() {
+        let script = export("0 tap 1 1", ExportFormat::Shell(ShellTarget::Device));
+        assert!(script.contains("adb push replay.device.sh /data/local/tmp/ && adb shell sh /data/local/tmp/replay.device.sh"));
+        assert!(script.contains("\ninput tap 1 1 &\n"));
+    }
+}
@@ -0,0 +1,418 @@
+use core::fmt;
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Debug)]
+pub enum ExpressionError {
+    Syntax(String),
+    UnknownVariable(String),
+    UnknownFunction(String),
+    DivisionByZero,
+    // rand(min, max) with max below min
+    EmptyRange,
+}
+
+// numbers without a '.' stay integers, so pixel coordinates don't turn into fractions of the screen
+#[derive(Clone, Copy, Debug)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+}
+
+impl Value {
+    fn as_f64(self) -> f64 {
+        match self {
+            Value::Int(i) => i as f64,
+            Value::Float(f) => f,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(v) => write!(f, "{:.4}", v),
+        }
+    }
+}
+
+// splitmix64, small and good enough for jitter. The same seed gives the same run
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Default)]
+pub struct Variables(Vec<(String, Value)>);
+
+impl Variables {
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, v)| *v)
+    }
+
+    pub fn set(&mut self, name: &str, value: Value) {
+        match self.0.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((name.to_string(), value)),
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// names `$name` can read back, checked when parsing "set"
+pub fn is_variable_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_name_char)
+}
+
+// true if the text has to be evaluated when playing
+pub fn is_expression(s: &str) -> bool {
+    s.contains('$') || s.contains("rand(")
+}
+
+// checks the syntax without evaluating, used when loading scripts
+pub fn check_line(s: &str) -> Result<(), ExpressionError> {
+    for word in split_words(s) {
+        if is_expression(word) {
+            check_expression(strip_unit(word).0)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn check_expression(s: &str) -> Result<(), ExpressionError> {
+    let mut parser = Parser { s, pos: 0, eval: None };
+    parser.parse_all().map(|_| ())
+}
+
+// replaces every expression word of the line with its value, other words are kept as written
+pub fn evaluate_line(s: &str, variables: &Variables, rng: &mut Rng) -> Result<String, ExpressionError> {
+    let mut res = String::new();
+    for word in split_words(s) {
+        if !res.is_empty() {
+            res.push(' ');
+        }
+        if is_expression(word) {
+            let (expression, unit) = strip_unit(word);
+            let value = match (evaluate(expression, variables, rng)?, unit) {
+                // without a unit the result is pixels or milliseconds, a fraction would read as normalized coordinate
+                (Value::Float(f), "") => Value::Int(f.round() as i64),
+                (value, _) => value,
+            };
+            res += &format!("{}{}", value, unit);
+        } else {
+            res += word;
+        }
+    }
+    Ok(res)
+}
+
+pub fn evaluate(s: &str, variables: &Variables, rng: &mut Rng) -> Result<Value, ExpressionError> {
+    let mut parser = Parser { s, pos: 0, eval: Some((variables, rng)) };
+    parser.parse_all()
+}
+
+// words are separated by whitespace outside of parentheses, so "rand(-5, 5)" stays one word
+fn split_words(s: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut depth = 0;
+    let mut start = None;
+    for (idx, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => (),
+        }
+        if c.is_whitespace() && depth <= 0 {
+            if let Some(start) = start.take() {
+                words.push(&s[start..idx]);
+            }
+        } else if start.is_none() {
+            start = Some(idx);
+        }
+    }
+    if let Some(start) = start {
+        words.push(&s[start..]);
+    }
+    words
+}
+
+// "($x+4)dp" and "($p+5)%" are evaluated without the unit, which is appended again.
+// A '%' at the end can't be the modulo operator, that needs a right side
+fn strip_unit(word: &str) -> (&str, &str) {
+    if let Some(expression) = word.strip_suffix("dp") {
+        (expression, "dp")
+    } else if let Some(expression) = word.strip_suffix('%') {
+        (expression, "%")
+    } else {
+        (word, "")
+    }
+}
+
+// recursive descent over
+// expression = term (('+' | '-') term)*
+// term       = factor (('*' | '/' | '%') factor)*
+// factor     = number | '$' name | name '(' expression (',' expression)* ')' | '(' expression ')' | '-' factor
+// without eval only the syntax is checked and every value is 0
+struct Parser<'a, 'b> {
+    s: &'a str,
+    pos: usize,
+    eval: Option<(&'b Variables, &'b mut Rng)>,
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn parse_all(&mut self) -> Result<Value, ExpressionError> {
+        let value = self.expression()?;
+        self.skip_whitespace();
+        match self.peek() {
+            None => Ok(value),
+            Some(c) => Err(self.syntax_error(c)),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Value, ExpressionError> {
+        let mut value = self.term()?;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(op @ ('+' | '-')) => {
+                    self.pos += 1;
+                    let rhs = self.term()?;
+                    value = apply(op, value, rhs)?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Value, ExpressionError> {
+        let mut value = self.factor()?;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(op @ ('*' | '/' | '%')) => {
+                    self.pos += 1;
+                    let rhs = self.factor()?;
+                    value = match self.eval {
+                        Some(_) => apply(op, value, rhs)?,
+                        None => value,
+                    };
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<Value, ExpressionError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                match self.factor()? {
+                    Value::Int(i) => Ok(Value::Int(i.wrapping_neg())),
+                    Value::Float(f) => Ok(Value::Float(-f)),
+                }
+            }
+            Some('(') => {
+                self.pos += 1;
+                let value = self.expression()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some('$') => {
+                self.pos += 1;
+                let name = self.name();
+                match &self.eval {
+                    Some((variables, _)) => variables.get(name).ok_or_else(|| ExpressionError::UnknownVariable(name.to_string())),
+                    None if name.is_empty() => Err(ExpressionError::Syntax(self.s.to_string())),
+                    None => Ok(Value::Int(0)),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() => {
+                let name = self.name();
+                self.expect('(')?;
+                let mut args = vec![self.expression()?];
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(',') => {
+                            self.pos += 1;
+                            args.push(self.expression()?);
+                        }
+                        _ => break,
+                    }
+                }
+                self.expect(')')?;
+                self.call(name, &args)
+            }
+            Some(c) => Err(self.syntax_error(c)),
+            None => Err(ExpressionError::Syntax(self.s.to_string())),
+        }
+    }
+
+    fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, ExpressionError> {
+        match (name, args) {
+            ("rand", [min, max]) => match &mut self.eval {
+                None => Ok(Value::Int(0)),
+                Some((_, rng)) => match (min, max) {
+                    (Value::Int(min), Value::Int(max)) if max < min => Err(ExpressionError::EmptyRange),
+                    // both ends included
+                    (Value::Int(min), Value::Int(max)) => {
+                        // the range of all i64 doesn't fit in u64
+                        let range = (*max as i128 - *min as i128 + 1) as u128;
+                        let offset = rng.next_u64() as u128 % range;
+                        Ok(Value::Int((*min as i128 + offset as i128) as i64))
+                    }
+                    _ if max.as_f64() < min.as_f64() => Err(ExpressionError::EmptyRange),
+                    _ => Ok(Value::Float(min.as_f64() + rng.next_f64() * (max.as_f64() - min.as_f64()))),
+                },
+            },
+            _ => Err(ExpressionError::UnknownFunction(name.to_string())),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, ExpressionError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_digit() || c == '.') {
+                break;
+            }
+            self.pos += 1;
+        }
+        let text = &self.s[start..self.pos];
+        if text.contains('.') {
+            text.parse().map(Value::Float).map_err(|_| ExpressionError::Syntax(text.to_string()))
+        } else {
+            text.parse().map(Value::Int).map_err(|_| ExpressionError::Syntax(text.to_string()))
+        }
+    }
+
+    fn name(&mut self) -> &'a str {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !is_name_char(c) {
+                break;
+            }
+            self.pos += 1;
+        }
+        &self.s[start..self.pos]
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ExpressionError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(found) if found == c => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(found) => Err(self.syntax_error(found)),
+            None => Err(ExpressionError::Syntax(self.s.to_string())),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn syntax_error(&self, c: char) -> ExpressionError {
+        ExpressionError::Syntax(format!("unexpected '{}' in {}", c, self.s))
+    }
+}
+
+fn apply(op: char, lhs: Value, rhs: Value) -> Result<Value, ExpressionError> {
+    match (lhs, rhs) {
+        (Value::Int(a), Value::Int(b)) => match op {
+            '+' => Ok(Value::Int(a.wrapping_add(b))),
+            '-' => Ok(Value::Int(a.wrapping_sub(b))),
+            '*' => Ok(Value::Int(a.wrapping_mul(b))),
+            _ if b == 0 => Err(ExpressionError::DivisionByZero),
+            '/' => Ok(Value::Int(a.wrapping_div(b))),
+            _ => Ok(Value::Int(a.wrapping_rem(b))),
+        },
+        (a, b) => {
+            let (a, b) = (a.as_f64(), b.as_f64());
+            match op {
+                '+' => Ok(Value::Float(a + b)),
+                '-' => Ok(Value::Float(a - b)),
+                '*' => Ok(Value::Float(a * b)),
+                _ if b == 0.0 => Err(ExpressionError::DivisionByZero),
+                '/' => Ok(Value::Float(a / b)),
+                _ => Ok(Value::Float(a % b)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str) -> Result<Value, ExpressionError> {
+        evaluate(s, &Variables::default(), &mut Rng::new(1))
+    }
+
+    #[test]
+    fn rand_over_all_of_i64() {
+        for _ in 0..10 {
+            assert!(matches!(eval("rand(0-9223372036854775807-1,9223372036854775807)"), Ok(Value::Int(_))));
+        }
+    }
+
+    #[test]
+    fn rand_includes_both_ends() {
+        let mut rng = Rng::new(7);
+        let values: Vec<i64> = (0..100)
+            .map(|_| match evaluate("rand(1,2)", &Variables::default(), &mut rng) {
+                Ok(Value::Int(i)) => i,
+                _ => panic!("not an integer"),
+            })
+            .collect();
+        assert!(values.contains(&1) && values.contains(&2));
+        assert!(matches!(eval("rand(2,1)"), Err(ExpressionError::EmptyRange)));
+    }
+
+    #[test]
+    fn errors_when_evaluating() {
+        assert!(matches!(eval("1/0"), Err(ExpressionError::DivisionByZero)));
+        assert!(matches!(eval("$missing+1"), Err(ExpressionError::UnknownVariable(_))));
+    }
+
+    #[test]
+    fn line_units() {
+        let mut variables = Variables::default();
+        variables.set("x", Value::Int(540));
+        let mut rng = Rng::new(0);
+        assert_eq!(evaluate_line("tap $x*1.5 $x/10%", &variables, &mut rng).ok().unwrap(), "tap 810 54%");
+        assert!(is_variable_name("x_1") && !is_variable_name("a-b"));
+    }
+}
@@ -4,39 +4,525 @@ use std::{
     str::FromStr,
 };
 
-use crate::{input_event::{InputEvent, InputEventInfo, TouchType}, input_event_recorder::ScreenInfo};
+use crate::{
+    device_entry::DeviceEntry,
+    expression::{check_expression, check_line, evaluate_line, is_expression, is_variable_name, ExpressionError, Rng, Variables},
+    input_event::{InputEvent, InputEventInfo, TouchType, MT_TOOL_PEN},
+    input_event_recorder::ScreenInfos,
+};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct InputWithTimestamp {
     pub input: Input,
     pub timestamp_milliseconds: u32,
+    // timestamp_milliseconds is the delay after the previous step instead of the time since the start, "+250ms"
+    #[serde(default)]
+    pub is_relative: bool,
+    // display to send the input to, `input -d <display_id>`, None uses the default display
+    #[serde(default)]
+    pub display_id: Option<i32>,
+    // source to send the input from, `input <source> ...`, None uses the default source of the command
+    #[serde(default)]
+    pub source: Option<InputSource>,
 }
 
 impl Display for InputWithTimestamp {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{:6} {}", self.timestamp_milliseconds, self.input)
+        fmt_timing(f, self.timestamp_milliseconds, self.is_relative)?;
+        if let Some(source) = self.source {
+            write!(f, "{} ", source)?;
+        }
+        if let Some(display_id) = self.display_id {
+            write!(f, "-d {} ", display_id)?;
+        }
+        write!(f, "{}", self.input)
+    }
+}
+
+impl InputWithTimestamp {
+    // the arguments of `input` that send the input, the coordinates of send_input must be pixels
+    pub fn input_args(&self, send_input: &Input) -> Vec<String> {
+        let (source, command) = match send_input {
+            // strokes are sent as swipe from the stylus source, other sources must be chosen explicitly
+            Input::Stroke(stroke) => (Some(self.source.unwrap_or(InputSource::Stylus)), format!("swipe {}", stroke.swipe)),
+            input => (self.source, input.to_string()),
+        };
+
+        let mut args = Vec::new();
+        if let Some(source) = source {
+            args.push(source.to_string());
+        }
+        if let Some(display_id) = self.display_id {
+            args.push("-d".to_string());
+            args.push(display_id.to_string());
+        }
+        args.extend(command.split_ascii_whitespace().map(str::to_string));
+        args
+    }
+}
+
+fn fmt_timing(f: &mut Formatter<'_>, timestamp_milliseconds: u32, is_relative: bool) -> fmt::Result {
+    match (is_relative, timestamp_milliseconds) {
+        // steps directly after the previous one, like waits, are written without a time
+        (true, 0) => Ok(()),
+        (true, ms) => write!(f, "+{}ms ", ms),
+        (false, ms) => write!(f, "{:6} ", ms),
     }
 }
 
+// the longest time, delay or wait a script may give, about 11 days. Sums of them still fit the u32 timeline
+pub const MAX_SCRIPT_MS: u32 = 1_000_000_000;
+
+fn parse_ms(s: &str) -> Result<u32, ()> {
+    let ms: u64 = s.parse().map_err(|_| ())?;
+    match ms <= MAX_SCRIPT_MS as u64 {
+        true => Ok(ms as u32),
+        false => Err(()),
+    }
+}
+
+// "+250ms rest" or "1200 rest", lines without a time are relative with no delay
+fn split_timing(s: &str) -> Result<(u32, bool, &str), ()> {
+    let s = s.trim();
+    let (first, rest) = s.split_once(' ').ok_or(())?;
+
+    if let Some(delay) = first.strip_prefix('+') {
+        let delay = delay.strip_suffix("ms").ok_or(())?;
+        Ok((parse_ms(delay)?, true, rest.trim()))
+    } else if first.parse::<u64>().is_ok() {
+        Ok((parse_ms(first)?, false, rest.trim()))
+    } else {
+        Ok((0, true, s))
+    }
+}
+
+// "mouse tap 10 20" -> (Mouse, "mouse", "tap 10 20")
+fn split_source(text: &str) -> Option<(InputSource, &str, &str)> {
+    let (first, rest) = text.split_once(' ')?;
+    Some((first.parse().ok()?, first, rest.trim()))
+}
+
 impl FromStr for InputWithTimestamp {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (first, second) = s.trim().split_once(' ').ok_or(())?;
-        let timestamp_milliseconds = first.parse().map_err(|_| ())?;
-        let input = second.trim().parse().map_err(|_| ())?;
+        let (timestamp_milliseconds, is_relative, mut second) = split_timing(s)?;
+
+        let mut source = None;
+        let mut source_word = None;
+        if let Some((s, word, rest)) = split_source(second) {
+            source = Some(s);
+            source_word = Some(word);
+            second = rest;
+        }
+
+        let mut display_id = None;
+        if let Some(rest) = second.strip_prefix("-d ") {
+            let (id, rest) = rest.trim().split_once(' ').ok_or(())?;
+            display_id = Some(id.parse().map_err(|_| ())?);
+            second = rest;
+        }
+
+        // older scripts wrote "mouse" after the display
+        if source.is_none() {
+            if let Some((s, word, rest)) = split_source(second) {
+                source = Some(s);
+                source_word = Some(word);
+                second = rest;
+            }
+        }
+
+        let input = match (second.trim().parse(), source_word) {
+            (Ok(input), _) => input,
+            // older scripts wrote strokes as "stylus <stroke>", the keyword reads like a source
+            (Err(()), Some(word)) => {
+                source = None;
+                format!("{} {}", word, second).parse()?
+            }
+            (Err(()), None) => return Err(()),
+        };
         Ok(Self {
             input,
             timestamp_milliseconds,
+            is_relative,
+            display_id,
+            source,
         })
     }
 }
 
-#[derive(Clone, Copy)]
+// a line of a script, annotations are kept so hand edited scripts survive being written back out
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub enum ScriptLine {
+    Input(InputWithTimestamp),
+    // "## name"
+    Section(String),
+    // "# text", the text is kept as written
+    Comment(String),
+    Blank,
+    // "repeat 3 {", runs the lines up to the matching "}" this many times
+    RepeatStart(u32),
+    // "}"
+    RepeatEnd,
+    // "label name", target of a goto
+    Label(String),
+    // "goto name"
+    Goto(String),
+    // an input with expressions, evaluated when playing
+    Template(InputTemplate),
+    // "set name = expression", evaluated when playing
+    Set { name: String, expression: String },
+    // "include \"login.air\"", runs another script here. The path is relative to the including script
+    Include(String),
+}
+
+// like InputWithTimestamp, but the input is kept as text until the expressions in it are evaluated
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct InputTemplate {
+    pub timestamp_milliseconds: u32,
+    pub is_relative: bool,
+    // everything after the time, "tap $x+rand(-5,5) $y"
+    pub text: String,
+}
+
+impl InputTemplate {
+    pub fn evaluate(&self, variables: &Variables, rng: &mut Rng) -> Result<InputWithTimestamp, ExpressionError> {
+        let text = evaluate_line(&self.text, variables, rng)?;
+        let mut input: InputWithTimestamp = text
+            .parse()
+            .map_err(|_| ExpressionError::Syntax(format!("not an input: {}", text)))?;
+        input.timestamp_milliseconds = self.timestamp_milliseconds;
+        input.is_relative = self.is_relative;
+        Ok(input)
+    }
+}
+
+impl Display for InputTemplate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_timing(f, self.timestamp_milliseconds, self.is_relative)?;
+        write!(f, "{}", self.text)
+    }
+}
+
+impl FromStr for InputTemplate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (timestamp_milliseconds, is_relative, text) = split_timing(s)?;
+        check_line(text).map_err(|_| ())?;
+        Ok(Self {
+            timestamp_milliseconds,
+            is_relative,
+            text: text.to_string(),
+        })
+    }
+}
+
+impl ScriptLine {
+    pub fn as_input(&self) -> Option<&InputWithTimestamp> {
+        match self {
+            ScriptLine::Input(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    // (timestamp_milliseconds, is_relative) of lines that have a time
+    pub fn timing(&self) -> Option<(u32, bool)> {
+        match self {
+            ScriptLine::Input(i) => Some((i.timestamp_milliseconds, i.is_relative)),
+            ScriptLine::Template(t) => Some((t.timestamp_milliseconds, t.is_relative)),
+            _ => None,
+        }
+    }
+
+    pub fn timing_mut(&mut self) -> Option<(&mut u32, &mut bool)> {
+        match self {
+            ScriptLine::Input(i) => Some((&mut i.timestamp_milliseconds, &mut i.is_relative)),
+            ScriptLine::Template(t) => Some((&mut t.timestamp_milliseconds, &mut t.is_relative)),
+            _ => None,
+        }
+    }
+
+    pub fn is_wait(&self) -> bool {
+        match self {
+            ScriptLine::Input(i) => matches!(i.input, Input::Wait(_)),
+            ScriptLine::Template(t) => t.text.starts_with("wait "),
+            _ => false,
+        }
+    }
+}
+
+impl Display for ScriptLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptLine::Input(i) => write!(f, "{}", i),
+            ScriptLine::Section(name) => write!(f, "## {}", name),
+            ScriptLine::Comment(text) => write!(f, "#{}", text),
+            ScriptLine::Blank => Ok(()),
+            ScriptLine::RepeatStart(count) => write!(f, "repeat {} {{", count),
+            ScriptLine::RepeatEnd => write!(f, "}}"),
+            ScriptLine::Label(name) => write!(f, "label {}", name),
+            ScriptLine::Goto(name) => write!(f, "goto {}", name),
+            ScriptLine::Template(t) => write!(f, "{}", t),
+            ScriptLine::Set { name, expression } => write!(f, "set {} = {}", name, expression),
+            ScriptLine::Include(path) => write!(f, "include \"{}\"", path),
+        }
+    }
+}
+
+impl FromStr for ScriptLine {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let line = s.trim_end_matches(['\r', '\n']);
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            Ok(ScriptLine::Blank)
+        } else if let Some(name) = trimmed.strip_prefix("##") {
+            Ok(ScriptLine::Section(name.trim().to_string()))
+        } else if let Some(text) = trimmed.strip_prefix('#') {
+            Ok(ScriptLine::Comment(text.to_string()))
+        } else if trimmed == "}" {
+            Ok(ScriptLine::RepeatEnd)
+        } else if let Some(count) = trimmed.strip_prefix("repeat ").and_then(|r| r.strip_suffix('{')) {
+            Ok(ScriptLine::RepeatStart(count.trim().parse().map_err(|_| ())?))
+        } else if let Some(name) = trimmed.strip_prefix("label ") {
+            Ok(ScriptLine::Label(name.trim().to_string()))
+        } else if let Some(name) = trimmed.strip_prefix("goto ") {
+            Ok(ScriptLine::Goto(name.trim().to_string()))
+        } else if let Some(path) = trimmed.strip_prefix("include ").or_else(|| trimmed.strip_prefix("call ")) {
+            let path = path.trim();
+            let path = path.strip_prefix('"').and_then(|p| p.strip_suffix('"')).unwrap_or(path);
+            Ok(ScriptLine::Include(path.to_string()))
+        } else if let Some(assignment) = trimmed.strip_prefix("set ") {
+            let (name, expression) = assignment.split_once('=').ok_or(())?;
+            if !is_variable_name(name.trim()) {
+                return Err(());
+            }
+            check_expression(expression).map_err(|_| ())?;
+            Ok(ScriptLine::Set { name: name.trim().to_string(), expression: expression.trim().to_string() })
+        } else if let Ok(input) = trimmed.parse() {
+            // plain inputs first, "shell echo $HOME" is not an expression
+            Ok(ScriptLine::Input(input))
+        } else if is_expression(trimmed) {
+            Ok(ScriptLine::Template(trimmed.parse()?))
+        } else {
+            Err(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum InputSource {
+    Touchscreen,
+    Touchpad,
+    TouchNavigation,
+    Stylus,
+    Mouse,
+    Trackball,
+    Keyboard,
+    Dpad,
+    Gamepad,
+    Joystick,
+    Gesture,
+    RotaryEncoder,
+}
+
+impl InputSource {
+    const ALL: [InputSource; 12] = [
+        InputSource::Touchscreen,
+        InputSource::Touchpad,
+        InputSource::TouchNavigation,
+        InputSource::Stylus,
+        InputSource::Mouse,
+        InputSource::Trackball,
+        InputSource::Keyboard,
+        InputSource::Dpad,
+        InputSource::Gamepad,
+        InputSource::Joystick,
+        InputSource::Gesture,
+        InputSource::RotaryEncoder,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            InputSource::Touchscreen => "touchscreen",
+            InputSource::Touchpad => "touchpad",
+            InputSource::TouchNavigation => "touchnavigation",
+            InputSource::Stylus => "stylus",
+            InputSource::Mouse => "mouse",
+            InputSource::Trackball => "trackball",
+            InputSource::Keyboard => "keyboard",
+            InputSource::Dpad => "dpad",
+            InputSource::Gamepad => "gamepad",
+            InputSource::Joystick => "joystick",
+            InputSource::Gesture => "gesture",
+            InputSource::RotaryEncoder => "rotaryencoder",
+        }
+    }
+
+    // AINPUT_SOURCE_* value as reported in the "Sources:" of `dumpsys input`, None if it has no own value
+    fn android_source(&self) -> Option<u32> {
+        match self {
+            InputSource::Touchscreen => Some(0x1002),
+            InputSource::Touchpad => Some(0x100008),
+            InputSource::TouchNavigation => Some(0x200000),
+            InputSource::Stylus => Some(0x4002),
+            InputSource::Mouse => Some(0x2002),
+            InputSource::Trackball => Some(0x10004),
+            InputSource::Keyboard => Some(0x101),
+            InputSource::Dpad => Some(0x201),
+            InputSource::Gamepad => Some(0x401),
+            InputSource::Joystick => Some(0x1000010),
+            InputSource::Gesture => None,
+            InputSource::RotaryEncoder => Some(0x400000),
+        }
+    }
+
+    pub fn is_in(&self, device_sources: u32) -> bool {
+        self.android_source()
+            .map(|s| device_sources & s == s)
+            .unwrap_or(false)
+    }
+
+    // parses "0x00001103" as well as "KEYBOARD | DPAD | TOUCHSCREEN", newer android versions print the names.
+    // Names not known here, like sources of newer versions or vendors, are skipped
+    pub fn parse_device_sources(s: &str) -> Option<u32> {
+        let s = s.trim();
+        if let Some(hex) = s.strip_prefix("0x") {
+            return u32::from_str_radix(hex, 16).ok();
+        }
+
+        let mut sources = 0;
+        for name in s.split('|') {
+            let name = name.trim().replace('_', "").to_ascii_lowercase();
+            if let Some(source) = Self::ALL.iter().find(|s| s.name() == name) {
+                sources |= source.android_source().unwrap_or(0);
+            }
+        }
+        Some(sources)
+    }
+}
+
+impl Display for InputSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for InputSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        Self::ALL
+            .iter()
+            .find(|source| source.name() == s)
+            .copied()
+            .ok_or(())
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub enum Input {
     Tap(Tap),
     Swipe(Swipe),
     Key(Key),
+    Scroll(Scroll),
+    Roll(Roll),
+    Stroke(Stroke),
+    // does nothing itself, delays all following steps
+    Wait(u32),
+    // any `adb shell` command, "shell settings put system screen_brightness 10"
+    Shell(String),
+    // starts an activity, "launch com.example/.MainActivity"
+    Launch(String),
+    // "force-stop com.example"
+    ForceStop(String),
+    // "clear-data com.example"
+    ClearData(String),
+    // opens a deep link with the VIEW intent, "open https://example.com/item/1"
+    OpenUri(String),
+}
+
+impl Input {
+    fn coordinates_mut(&mut self) -> Vec<(&mut Coordinate, bool)> {
+        // (coordinate, is_x)
+        match self {
+            Input::Tap(t) => vec![(&mut t.x, true), (&mut t.y, false)],
+            Input::Swipe(s) | Input::Stroke(Stroke { swipe: s, .. }) => {
+                let [x0, x1] = &mut s.x;
+                let [y0, y1] = &mut s.y;
+                vec![(x0, true), (y0, false), (x1, true), (y1, false)]
+            }
+            Input::Scroll(s) => vec![(&mut s.x, true), (&mut s.y, false)],
+            Input::Key(_) | Input::Roll(_) | Input::Wait(_) => Vec::new(),
+            Input::Shell(_) | Input::Launch(_) | Input::ForceStop(_) | Input::ClearData(_) | Input::OpenUri(_) => Vec::new(),
+        }
+    }
+
+    // the command run by `adb shell` for steps that are not sent with `input`
+    pub fn shell_command(&self, display_id: Option<i32>) -> Option<String> {
+        let display = display_id.map(|id| format!("--display {} ", id)).unwrap_or_default();
+        match self {
+            Input::Shell(command) => Some(command.clone()),
+            // -W waits until the activity is shown, so the next step doesn't run against the old screen
+            Input::Launch(component) => Some(format!("am start -W {}-n {}", display, component)),
+            Input::ForceStop(package) => Some(format!("am force-stop {}", package)),
+            Input::ClearData(package) => Some(format!("pm clear {}", package)),
+            Input::OpenUri(uri) => Some(format!(
+                "am start -W {}-a android.intent.action.VIEW -d '{}'",
+                display,
+                uri.replace('\'', "'\\''")
+            )),
+            _ => None,
+        }
+    }
+
+    // true if the display has to be known to send the input
+    pub fn needs_display_metrics(&self) -> bool {
+        let coordinates = match self {
+            Input::Tap(t) => vec![t.x, t.y],
+            Input::Swipe(s) | Input::Stroke(Stroke { swipe: s, .. }) => vec![s.x[0], s.y[0], s.x[1], s.y[1]],
+            Input::Scroll(s) => vec![s.x, s.y],
+            Input::Key(_) | Input::Roll(_) | Input::Wait(_) => Vec::new(),
+            Input::Shell(_) | Input::Launch(_) | Input::ForceStop(_) | Input::ClearData(_) | Input::OpenUri(_) => Vec::new(),
+        };
+        coordinates.iter().any(|c| !c.is_pixels())
+    }
+
+    // converts pixel coordinates to fractions of the display size
+    pub fn normalize(&mut self, (width, height): (i32, i32)) {
+        for (c, is_x) in self.coordinates_mut() {
+            if let Coordinate::Pixels(p) = *c {
+                *c = Coordinate::normalized(p, if is_x { width } else { height });
+            }
+        }
+    }
+
+    // makes swipes and strokes take 1 / factor of their recorded time
+    pub fn scale_duration(&mut self, factor: f32) {
+        let scale = |milliseconds: u32| (milliseconds as f32 / factor).round() as u32;
+        match self {
+            Input::Swipe(s) => s.milliseconds = scale(s.milliseconds),
+            Input::Stroke(s) => {
+                s.swipe.milliseconds = scale(s.swipe.milliseconds);
+                for p in &mut s.points {
+                    p.milliseconds = scale(p.milliseconds);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // converts all coordinates to pixels of the given display
+    pub fn to_pixels(&mut self, metrics: DisplayMetrics) {
+        for (c, is_x) in self.coordinates_mut() {
+            let extent = if is_x { metrics.width } else { metrics.height };
+            *c = Coordinate::Pixels(c.to_pixels(extent, metrics.density));
+        }
+    }
 }
 
 impl Display for Input {
@@ -45,6 +531,15 @@ impl Display for Input {
             Input::Tap(i) => write!(f, "{:6} {}", "tap", i),
             Input::Swipe(i) => write!(f, "{:6} {}", "swipe", i),
             Input::Key(i) => write!(f, "{:6} {}", "keyevent", i),
+            Input::Wait(ms) => write!(f, "{:6} {}", "wait", ms),
+            Input::Scroll(i) => write!(f, "{:6} {}", "scroll", i),
+            Input::Roll(i) => write!(f, "{:6} {}", "roll", i),
+            Input::Stroke(i) => write!(f, "{:6} {}", "stroke", i),
+            Input::Shell(command) => write!(f, "{:6} {}", "shell", command),
+            Input::Launch(component) => write!(f, "{:6} {}", "launch", component),
+            Input::ForceStop(package) => write!(f, "{:6} {}", "force-stop", package),
+            Input::ClearData(package) => write!(f, "{:6} {}", "clear-data", package),
+            Input::OpenUri(uri) => write!(f, "{:6} {}", "open", uri),
         }
     }
 }
@@ -59,16 +554,111 @@ impl FromStr for Input {
             "tap" => Self::Tap(second.parse()?),
             "swipe" => Self::Swipe(second.parse()?),
             "keyevent" => Self::Key(second.parse()?),
+            "wait" => Self::Wait(parse_ms(second.trim())?),
+            "scroll" => Self::Scroll(second.parse()?),
+            "roll" => Self::Roll(second.parse()?),
+            // "stylus" is the keyword of older scripts
+            "stroke" | "stylus" => Self::Stroke(second.parse()?),
+            // '$' belongs to the shell, it is not a variable here
+            "shell" => Self::Shell(second.trim().to_string()),
+            "launch" => Self::Launch(parse_argument(second)?),
+            "force-stop" => Self::ForceStop(parse_argument(second)?),
+            "clear-data" => Self::ClearData(parse_argument(second)?),
+            "open" => Self::OpenUri(parse_argument(second)?),
             _ => return Err(()),
         };
         Ok(res)
     }
 }
 
+// a single word, arguments with variables are left to InputTemplate
+fn parse_argument(s: &str) -> Result<String, ()> {
+    let s = s.trim();
+    if s.is_empty() || s.contains(char::is_whitespace) || is_expression(s) {
+        return Err(());
+    }
+    Ok(s.to_string())
+}
+
+// a position on the screen, only pixels can be sent to the device, the others are scaled to the display at play time
+#[derive(Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Coordinate {
+    // "540"
+    Pixels(i32),
+    // "0.5000", fraction of the display width or height
+    Normalized(f32),
+    // "120dp", density independent pixels
+    Dp(f32),
+}
+
+impl Coordinate {
+    pub fn normalized(pixels: i32, extent: i32) -> Self {
+        if extent <= 0 {
+            return Coordinate::Pixels(pixels);
+        }
+        Coordinate::Normalized(pixels as f32 / extent as f32)
+    }
+
+    // extent is the display width or height in pixels, density as reported by `wm density`
+    pub fn to_pixels(self, extent: i32, density: i32) -> i32 {
+        match self {
+            Coordinate::Pixels(p) => p,
+            Coordinate::Normalized(n) => (n * extent as f32).round() as i32,
+            Coordinate::Dp(dp) => (dp * density as f32 / 160.0).round() as i32,
+        }
+    }
+
+    pub fn is_pixels(&self) -> bool {
+        matches!(self, Coordinate::Pixels(_))
+    }
+}
+
+impl From<i32> for Coordinate {
+    fn from(pixels: i32) -> Self {
+        Coordinate::Pixels(pixels)
+    }
+}
+
+impl Display for Coordinate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Coordinate::Pixels(p) => Display::fmt(p, f),
+            Coordinate::Normalized(n) => f.pad(&format!("{:.4}", n)),
+            Coordinate::Dp(dp) => f.pad(&format!("{}dp", dp)),
+        }
+    }
+}
+
+impl FromStr for Coordinate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(dp) = s.strip_suffix("dp") {
+            Ok(Coordinate::Dp(dp.parse().map_err(|_| ())?))
+        } else if let Some(percent) = s.strip_suffix('%') {
+            // "50%" is the same as "0.5000"
+            Ok(Coordinate::Normalized(percent.parse::<f32>().map_err(|_| ())? / 100.0))
+        } else if s.contains('.') {
+            Ok(Coordinate::Normalized(s.parse().map_err(|_| ())?))
+        } else {
+            Ok(Coordinate::Pixels(s.parse().map_err(|_| ())?))
+        }
+    }
+}
+
+// what is needed to turn coordinates into pixels, width and height are in the current orientation
 #[derive(Clone, Copy)]
+pub struct DisplayMetrics {
+    pub width: i32,
+    pub height: i32,
+    pub density: i32,
+}
+
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
 pub struct Tap {
-    pub x: i32,
-    pub y: i32,
+    pub x: Coordinate,
+    pub y: Coordinate,
 }
 
 impl Display for Tap {
@@ -89,10 +679,10 @@ impl FromStr for Tap {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
 pub struct Swipe {
-    pub x: [i32; 2],
-    pub y: [i32; 2],
+    pub x: [Coordinate; 2],
+    pub y: [Coordinate; 2],
     pub milliseconds: u32,
 }
 
@@ -126,12 +716,279 @@ impl FromStr for Swipe {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct Scroll {
+    pub x: Coordinate,
+    pub y: Coordinate,
+    pub vertical: i32,
+    pub horizontal: i32,
+}
+
+impl Display for Scroll {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:4} {:4} --axis VSCROLL,{} --axis HSCROLL,{}",
+            self.x, self.y, self.vertical, self.horizontal
+        )
+    }
+}
+
+impl FromStr for Scroll {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut iter = s.trim().split_ascii_whitespace();
+
+        let x = iter.next().ok_or(())?.parse().map_err(|_| ())?;
+        let y = iter.next().ok_or(())?.parse().map_err(|_| ())?;
+
+        let mut vertical = 0;
+        let mut horizontal = 0;
+        while let Some(tok) = iter.next() {
+            if tok != "--axis" {
+                return Err(());
+            }
+            let (axis, value) = iter.next().ok_or(())?.split_once(',').ok_or(())?;
+            let value = value.parse().map_err(|_| ())?;
+            match axis {
+                "VSCROLL" => vertical = value,
+                "HSCROLL" => horizontal = value,
+                _ => return Err(()),
+            }
+        }
+
+        Ok(Self {
+            x,
+            y,
+            vertical,
+            horizontal,
+        })
+    }
+}
+
+// trackball movement, `input roll <dx> <dy>`
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct Roll {
+    pub dx: i32,
+    pub dy: i32,
+}
+
+impl Display for Roll {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:4} {:4}", self.dx, self.dy)
+    }
+}
+
+impl FromStr for Roll {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, second) = s.trim().split_once(' ').ok_or(())?;
+        Ok(Self {
+            dx: first.trim().parse().map_err(|_| ())?,
+            dy: second.trim().parse().map_err(|_| ())?,
+        })
+    }
+}
+
+// a pen stroke, the swipe holds the start and end in screen coordinates, the points hold the raw device values
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct Stroke {
+    pub swipe: Swipe,
+    pub event_nr: i32,
+    pub points: Vec<StrokePoint>,
+}
+
+impl Display for Stroke {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ev{}", self.swipe, self.event_nr)?;
+        for p in &self.points {
+            write!(f, " {}", p)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Stroke {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens: Vec<&str> = s.trim().split_ascii_whitespace().collect();
+        if tokens.len() < 6 {
+            return Err(());
+        }
+
+        let swipe = tokens[..5].join(" ").parse()?;
+        let event_nr = tokens[5]
+            .strip_prefix("ev")
+            .ok_or(())?
+            .parse()
+            .map_err(|_| ())?;
+        let points = tokens[6..]
+            .iter()
+            .map(|t| t.parse())
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            swipe,
+            event_nr,
+            points,
+        })
+    }
+}
+
+impl Stroke {
+    // only sendevent can replay pressure, `input stylus swipe` sends the start and end point
+    pub fn has_pressure(&self) -> bool {
+        self.points.iter().any(|p| p.pressure > 0)
+    }
+
+    // the points are raw device values in the natural orientation of the recorded display,
+    // from and to are the natural (width, height) of the recorded and the played display
+    pub fn scale_points(&mut self, from: (i32, i32), to: (i32, i32)) {
+        if from.0 <= 0 || from.1 <= 0 || from == to {
+            return;
+        }
+        for p in &mut self.points {
+            p.x = (p.x as f32 * to.0 as f32 / from.0 as f32).round() as i32;
+            p.y = (p.y as f32 * to.1 as f32 / from.1 as f32).round() as i32;
+        }
+    }
+
+    // sendevent is the only way to replay pressure and tilt. Codes a device does not support are dropped by the kernel,
+    // so both the single touch and the multi touch axes are sent
+    pub fn to_sendevent_script(&self) -> String {
+        use std::fmt::Write;
+
+        const EV_SYN: u16 = 0;
+        const EV_KEY: u16 = 1;
+        const EV_ABS: u16 = 3;
+        const SYN_REPORT: u16 = 0;
+        const BTN_TOOL_PEN: u16 = 0x140;
+        const BTN_TOUCH: u16 = 0x14a;
+        const BTN_STYLUS: u16 = 0x14b;
+        const ABS_X: u16 = 0x00;
+        const ABS_Y: u16 = 0x01;
+        const ABS_PRESSURE: u16 = 0x18;
+        const ABS_TILT_X: u16 = 0x1a;
+        const ABS_TILT_Y: u16 = 0x1b;
+        const ABS_MT_SLOT: u16 = 0x2f;
+        const ABS_MT_POSITION_X: u16 = 0x35;
+        const ABS_MT_POSITION_Y: u16 = 0x36;
+        const ABS_MT_TOOL_TYPE: u16 = 0x37;
+        const ABS_MT_TRACKING_ID: u16 = 0x39;
+        const ABS_MT_PRESSURE: u16 = 0x3a;
+
+        let mut script = String::new();
+        let send = |script: &mut String, ev_type: u16, code: u16, value: i32| {
+            write!(script, "sendevent /dev/input/event{} {} {} {};", self.event_nr, ev_type, code, value).expect("should not fail");
+        };
+
+        send(&mut script, EV_ABS, ABS_MT_SLOT, 0);
+        send(&mut script, EV_ABS, ABS_MT_TRACKING_ID, 0);
+        send(&mut script, EV_ABS, ABS_MT_TOOL_TYPE, MT_TOOL_PEN);
+        send(&mut script, EV_KEY, BTN_TOOL_PEN, 1);
+        send(&mut script, EV_KEY, BTN_TOUCH, 1);
+
+        let mut last_millis = 0;
+        for p in &self.points {
+            let diff = p.milliseconds.saturating_sub(last_millis);
+            last_millis = p.milliseconds;
+            if diff > 0 {
+                write!(script, "sleep {}.{:03};", diff / 1000, diff % 1000).expect("should not fail");
+            }
+
+            send(&mut script, EV_ABS, ABS_MT_POSITION_X, p.x);
+            send(&mut script, EV_ABS, ABS_MT_POSITION_Y, p.y);
+            send(&mut script, EV_ABS, ABS_X, p.x);
+            send(&mut script, EV_ABS, ABS_Y, p.y);
+            send(&mut script, EV_ABS, ABS_MT_PRESSURE, p.pressure);
+            send(&mut script, EV_ABS, ABS_PRESSURE, p.pressure);
+            send(&mut script, EV_ABS, ABS_TILT_X, p.tilt_x);
+            send(&mut script, EV_ABS, ABS_TILT_Y, p.tilt_y);
+            send(&mut script, EV_KEY, BTN_STYLUS, p.button as i32);
+            send(&mut script, EV_SYN, SYN_REPORT, 0);
+        }
+
+        send(&mut script, EV_ABS, ABS_MT_PRESSURE, 0);
+        send(&mut script, EV_ABS, ABS_PRESSURE, 0);
+        send(&mut script, EV_ABS, ABS_MT_TRACKING_ID, -1);
+        send(&mut script, EV_KEY, BTN_STYLUS, 0);
+        send(&mut script, EV_KEY, BTN_TOUCH, 0);
+        send(&mut script, EV_KEY, BTN_TOOL_PEN, 0);
+        send(&mut script, EV_SYN, SYN_REPORT, 0);
+
+        script
+    }
+}
+
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct StrokePoint {
+    // time since the pen touched down
+    pub milliseconds: u32,
+    pub x: i32,
+    pub y: i32,
+    pub pressure: i32,
+    pub tilt_x: i32,
+    pub tilt_y: i32,
+    pub button: bool,
+}
+
+impl Display for StrokePoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}:{}:{}:{}",
+            self.milliseconds,
+            self.x,
+            self.y,
+            self.pressure,
+            self.tilt_x,
+            self.tilt_y,
+            self.button as u8
+        )
+    }
+}
+
+impl FromStr for StrokePoint {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut iter = s.trim().split(':');
+
+        let milliseconds = iter.next().ok_or(())?.parse().map_err(|_| ())?;
+        let x = iter.next().ok_or(())?.parse().map_err(|_| ())?;
+        let y = iter.next().ok_or(())?.parse().map_err(|_| ())?;
+        let pressure = iter.next().ok_or(())?.parse().map_err(|_| ())?;
+        let tilt_x = iter.next().ok_or(())?.parse().map_err(|_| ())?;
+        let tilt_y = iter.next().ok_or(())?.parse().map_err(|_| ())?;
+        let button = match iter.next().ok_or(())? {
+            "0" => false,
+            "1" => true,
+            _ => return Err(()),
+        };
+
+        Ok(Self {
+            milliseconds,
+            x,
+            y,
+            pressure,
+            tilt_x,
+            tilt_y,
+            button,
+        })
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub enum Key {
     Power,
     Back,
     Home,
     Menu,
+    // any other "KEYCODE_..." name or a key code number, passed to `input keyevent` as written
+    Code(String),
 }
 
 impl Display for Key {
@@ -141,6 +998,7 @@ impl Display for Key {
             Key::Back => write!(f, "KEYCODE_BACK"),
             Key::Home => write!(f, "KEYCODE_HOME"),
             Key::Menu => write!(f, "KEYCODE_MENU"),
+            Key::Code(code) => write!(f, "{}", code),
         }
     }
 }
@@ -154,6 +1012,7 @@ impl FromStr for Key {
             "KEYCODE_BACK" => Self::Back,
             "KEYCODE_HOME" => Self::Home,
             "KEYCODE_MENU" => Self::Menu,
+            code if code.starts_with("KEYCODE_") || code.parse::<u32>().is_ok() => Self::Code(code.to_string()),
             _ => return Err(()),
         };
 
@@ -165,86 +1024,352 @@ pub fn convert_events_to_input(
     inputs: &[InputEventInfo],
     tap_threshold_distance : u32,
     tap_threshold_ms : u32,
-    screen_info : ScreenInfo,
+    screen_infos : &ScreenInfos,
+    devices : &[DeviceEntry],
 ) -> Vec<InputWithTimestamp> {
-    struct DownInput {
-        x : i32,
-        y : i32,
-        time : u32,
-    }
+    let mut converter = EventConverter::new(tap_threshold_distance, tap_threshold_ms, screen_infos.clone());
 
-    let first_time_stamp = match inputs.get(0) {
-        Some(x) => x.timestamp_milliseconds,
-        None => return Vec::new(),
-    };
+    // with the whole log known, relative motion of a mouse before its first click isn't taken for a trackball
+    for e in inputs.iter() {
+        converter.learn_device(e);
+    }
 
     let mut result = Vec::new();
+    for e in inputs.iter() {
+        result.extend(converter.push(e, devices));
+    }
+    result.extend(converter.finish());
+    result
+}
+
+struct DownInput {
+    x : i32,
+    y : i32,
+    time : u32,
+}
+
+struct StrokeInput {
+    down: DownInput,
+    event_nr: i32,
+    points: Vec<StrokePoint>,
+}
+
+// turns events into inputs one at a time, so gestures are known while recording as soon as they are finished
+pub struct EventConverter {
+    tap_threshold_distance : u32,
+    tap_threshold_ms : u32,
+    screen_infos : ScreenInfos,
+
+    first_time_stamp : Option<u32>,
 
     // we cann only track on finger, so only track touch input while slot 0 is active
-    let mut is_slot_0_active = true;
+    is_slot_0_active : bool,
 
-    let mut down : Option<DownInput> = None;
-    let mut last_x = 0;
-    let mut last_y = 0;
+    down : Option<DownInput>,
+    last_x : i32,
+    last_y : i32,
 
+    // devices reporting mouse buttons or a wheel are mice, relative motion of any other device is a trackball
+    mouse_event_nrs : Vec<i32>,
+    cursor_x : i32,
+    cursor_y : i32,
+    mouse_down : Option<DownInput>,
 
-    for e in inputs.iter() {
-        let relative_time_stamp = e.timestamp_milliseconds - first_time_stamp;
+    // a pen is either reported as multi touch tool type or as BTN_TOOL_PEN of a digitizer
+    is_pen : bool,
+    pen_point : StrokePoint,
+    stroke : Option<StrokeInput>,
+
+    // converted inputs not returned yet
+    result : Vec<InputWithTimestamp>,
+}
+
+impl EventConverter {
+    pub fn new(tap_threshold_distance : u32, tap_threshold_ms : u32, screen_infos : ScreenInfos) -> Self {
+        // the cursor position can't be queried, so assume it starts in the center of the screen
+        let (display_width, display_height) = screen_infos.default_display().display_size();
+
+        Self {
+            tap_threshold_distance,
+            tap_threshold_ms,
+            screen_infos,
+            first_time_stamp: None,
+            is_slot_0_active: true,
+            down: None,
+            last_x: 0,
+            last_y: 0,
+            mouse_event_nrs: Vec::new(),
+            cursor_x: display_width / 2,
+            cursor_y: display_height / 2,
+            mouse_down: None,
+            is_pen: false,
+            pen_point: StrokePoint { milliseconds: 0, x: 0, y: 0, pressure: 0, tilt_x: 0, tilt_y: 0, button: false },
+            stroke: None,
+            result: Vec::new(),
+        }
+    }
+
+    fn learn_device(&mut self, e: &InputEventInfo) {
+        if let InputEvent::BtnLeft(_) | InputEvent::BtnRight(_) | InputEvent::RelWheel(_) | InputEvent::RelHWheel(_) = e.event {
+            if !self.mouse_event_nrs.contains(&e.event_nr) {
+                self.mouse_event_nrs.push(e.event_nr);
+            }
+        }
+    }
+
+    // returns the inputs the event finished, devices are the ones getevent listed so far
+    pub fn push(&mut self, e: &InputEventInfo, devices: &[DeviceEntry]) -> Vec<InputWithTimestamp> {
+        self.learn_device(e);
+
+        let first_time_stamp = *self.first_time_stamp.get_or_insert(e.timestamp_milliseconds);
+        // logs joined from several sources can go back in time
+        let relative_time_stamp = e.timestamp_milliseconds.saturating_sub(first_time_stamp);
+
+        let screen_infos = &self.screen_infos;
+        let (display_width, display_height) = screen_infos.default_display().display_size();
+
+        // only name the display when there is a choice, so scripts of single display devices stay portable
+        let screen_info = screen_infos.for_device(devices, e.event_nr);
+        let display_id = screen_infos.has_multiple_displays().then_some(screen_info.display_id);
+
+        // replay from the same kind of device the events came from, the first candidate the device reports wins
+        let device_sources = screen_infos.sources_for_device(devices, e.event_nr);
+        let source_of = |candidates: &[InputSource]| candidates.iter().copied().find(|s| s.is_in(device_sources));
+        let touch_source = source_of(&[InputSource::Touchscreen, InputSource::Touchpad, InputSource::TouchNavigation]);
+
+        let result = &mut self.result;
         match e.event {
-            InputEvent::AbsMtSlot(slot) => is_slot_0_active = slot == 0,
-            InputEvent::AbsMtPosX(x) if is_slot_0_active => {
-                last_x = x;
+            InputEvent::AbsMtSlot(slot) => self.is_slot_0_active = slot == 0,
+            InputEvent::AbsMtPosX(x) if self.is_slot_0_active => {
+                self.last_x = x;
             }
-            InputEvent::AbsMtPosY(y) if is_slot_0_active => {
-                last_y = y;               
+            InputEvent::AbsMtPosY(y) if self.is_slot_0_active => {
+                self.last_y = y;
             }
-            InputEvent::BtnTouch(t) if is_slot_0_active => match t {
+            InputEvent::AbsMtToolType(tool) if self.is_slot_0_active => self.is_pen = tool == MT_TOOL_PEN,
+            InputEvent::BtnToolPen(t) => self.is_pen = t == TouchType::Down,
+            InputEvent::AbsX(x) if self.is_pen => self.last_x = x,
+            InputEvent::AbsY(y) if self.is_pen => self.last_y = y,
+            InputEvent::AbsMtPressure(p) if self.is_slot_0_active => self.pen_point.pressure = p,
+            InputEvent::AbsPressure(p) => self.pen_point.pressure = p,
+            InputEvent::AbsTiltX(tilt) => self.pen_point.tilt_x = tilt,
+            InputEvent::AbsTiltY(tilt) => self.pen_point.tilt_y = tilt,
+            InputEvent::BtnStylus(t) => self.pen_point.button = t == TouchType::Down,
+            InputEvent::SynReport => {
+                if let Some(s) = self.stroke.as_mut().filter(|s| s.event_nr == e.event_nr) {
+                    s.points.push(StrokePoint {
+                        milliseconds: relative_time_stamp.saturating_sub(s.down.time),
+                        x: self.last_x,
+                        y: self.last_y,
+                        ..self.pen_point
+                    });
+                }
+            }
+            InputEvent::BtnTouch(t) if self.is_slot_0_active && (self.is_pen || self.stroke.is_some()) => match t {
+                TouchType::Up => {
+                    if let Some(mut s) = self.stroke.take() {
+                        let down_dur_ms = relative_time_stamp.saturating_sub(s.down.time);
+                        s.points.push(StrokePoint { milliseconds: down_dur_ms, x: self.last_x, y: self.last_y, ..self.pen_point });
+
+                        let (start_x, start_y) = screen_info.remap((s.down.x, s.down.y));
+                        let (end_x, end_y) = screen_info.remap((self.last_x, self.last_y));
+
+                        result.push(InputWithTimestamp {
+                            timestamp_milliseconds: s.down.time,
+                            is_relative: false,
+                            display_id,
+                            source: Some(InputSource::Stylus),
+                            input: Input::Stroke(Stroke {
+                                swipe: Swipe {
+                                    milliseconds: down_dur_ms,
+                                    x: [start_x.into(), end_x.into()],
+                                    y: [start_y.into(), end_y.into()],
+                                },
+                                event_nr: s.event_nr,
+                                points: s.points,
+                            }),
+                        })
+                    }
+                }
+                TouchType::Down => {
+                    self.stroke = Some(StrokeInput {
+                        down: DownInput {x: self.last_x, y: self.last_y, time: relative_time_stamp},
+                        event_nr: e.event_nr,
+                        points: Vec::new(),
+                    });
+                }
+            },
+            InputEvent::BtnTouch(t) if self.is_slot_0_active => match t {
                 TouchType::Up => {
-                    if let Some(d) = down.take() {
+                    if let Some(d) = self.down.take() {
 
-                        let distance_moved = (d.x).abs_diff(last_x) + (d.y).abs_diff(last_y);
-                        let down_dur_ms = relative_time_stamp - d.time;
+                        let distance_moved = (d.x).abs_diff(self.last_x) + (d.y).abs_diff(self.last_y);
+                        let down_dur_ms = relative_time_stamp.saturating_sub(d.time);
 
-                        let is_swipe = distance_moved > tap_threshold_distance || down_dur_ms > tap_threshold_ms;
+                        let is_swipe = distance_moved > self.tap_threshold_distance || down_dur_ms > self.tap_threshold_ms;
 
                         if is_swipe {
                             let (start_x, start_y) = screen_info.remap((d.x, d.y));
-                            let (end_x, end_y) = screen_info.remap((last_x, last_y));
+                            let (end_x, end_y) = screen_info.remap((self.last_x, self.last_y));
 
                             result.push(InputWithTimestamp {
                                 timestamp_milliseconds: d.time,
+                                is_relative: false,
+                                display_id,
+                                source: touch_source,
                                 input: Input::Swipe(Swipe {
                                     milliseconds: down_dur_ms,
-                                    x: [start_x, end_x],
-                                    y: [start_y, end_y],
+                                    x: [start_x.into(), end_x.into()],
+                                    y: [start_y.into(), end_y.into()],
                                 }),
                             })
                         } else {
                             let (start_x, start_y) = screen_info.remap((d.x, d.y));
                             result.push(InputWithTimestamp {
                                 timestamp_milliseconds: d.time,
-                                input: Input::Tap(Tap { x : start_x, y : start_y, }),
+                                is_relative: false,
+                                display_id,
+                                source: touch_source,
+                                input: Input::Tap(Tap { x : start_x.into(), y : start_y.into(), }),
                             })
                         }
                     }
                 }
                 TouchType::Down => {
-                    down = Some(DownInput {x: last_x, y:last_y, time: relative_time_stamp});
+                    self.down = Some(DownInput {x: self.last_x, y: self.last_y, time: relative_time_stamp});
                 }
             },
             InputEvent::KeyPower(t) if t == TouchType::Down => {
                 result.push(InputWithTimestamp {
                     timestamp_milliseconds: relative_time_stamp,
+                    is_relative: false,
+                    display_id: None,
+                    source: source_of(&[InputSource::Gamepad, InputSource::Dpad, InputSource::Keyboard]),
                     input: Input::Key(Key::Power),
                 });
             }
+            InputEvent::RelX(dx) if self.mouse_event_nrs.contains(&e.event_nr) => {
+                self.cursor_x = (self.cursor_x + dx).clamp(0, (display_width - 1).max(0));
+            }
+            InputEvent::RelY(dy) if self.mouse_event_nrs.contains(&e.event_nr) => {
+                self.cursor_y = (self.cursor_y + dy).clamp(0, (display_height - 1).max(0));
+            }
+            InputEvent::RelX(dx) => {
+                push_or_merge(result, InputWithTimestamp {
+                    timestamp_milliseconds: relative_time_stamp,
+                    is_relative: false,
+                    display_id: None,
+                    source: Some(InputSource::Trackball),
+                    input: Input::Roll(Roll { dx, dy: 0 }),
+                });
+            }
+            InputEvent::RelY(dy) => {
+                push_or_merge(result, InputWithTimestamp {
+                    timestamp_milliseconds: relative_time_stamp,
+                    is_relative: false,
+                    display_id: None,
+                    source: Some(InputSource::Trackball),
+                    input: Input::Roll(Roll { dx: 0, dy }),
+                });
+            }
+            InputEvent::RelWheel(vertical) => {
+                push_or_merge(result, InputWithTimestamp {
+                    timestamp_milliseconds: relative_time_stamp,
+                    is_relative: false,
+                    display_id,
+                    source: Some(InputSource::Mouse),
+                    input: Input::Scroll(Scroll { x: self.cursor_x.into(), y: self.cursor_y.into(), vertical, horizontal: 0 }),
+                });
+            }
+            InputEvent::RelHWheel(horizontal) => {
+                push_or_merge(result, InputWithTimestamp {
+                    timestamp_milliseconds: relative_time_stamp,
+                    is_relative: false,
+                    display_id,
+                    source: Some(InputSource::Mouse),
+                    input: Input::Scroll(Scroll { x: self.cursor_x.into(), y: self.cursor_y.into(), vertical: 0, horizontal }),
+                });
+            }
+            InputEvent::BtnLeft(t) => match t {
+                TouchType::Up => {
+                    if let Some(d) = self.mouse_down.take() {
+                        let distance_moved = (d.x).abs_diff(self.cursor_x) + (d.y).abs_diff(self.cursor_y);
+                        let down_dur_ms = relative_time_stamp.saturating_sub(d.time);
+
+                        let is_swipe = distance_moved > self.tap_threshold_distance || down_dur_ms > self.tap_threshold_ms;
+
+                        let input = if is_swipe {
+                            Input::Swipe(Swipe {
+                                milliseconds: down_dur_ms,
+                                x: [d.x.into(), self.cursor_x.into()],
+                                y: [d.y.into(), self.cursor_y.into()],
+                            })
+                        } else {
+                            Input::Tap(Tap { x: d.x.into(), y: d.y.into() })
+                        };
+
+                        result.push(InputWithTimestamp {
+                            timestamp_milliseconds: d.time,
+                            is_relative: false,
+                            display_id,
+                            source: Some(InputSource::Mouse),
+                            input,
+                        });
+                    }
+                }
+                TouchType::Down => {
+                    self.mouse_down = Some(DownInput {x: self.cursor_x, y: self.cursor_y, time: relative_time_stamp});
+                }
+            },
+            // android maps the secondary mouse button to back by default
+            InputEvent::BtnRight(TouchType::Down) => {
+                result.push(InputWithTimestamp {
+                    timestamp_milliseconds: relative_time_stamp,
+                    is_relative: false,
+                    display_id,
+                    source: None,
+                    input: Input::Key(Key::Back),
+                });
+            }
             _ => (),
         }
+
+        // rolls and scrolls may still get the other axis of their report merged in
+        let is_open = matches!(result.last(), Some(InputWithTimestamp { input: Input::Roll(_) | Input::Scroll(_), .. }));
+        let finished = result.len() - is_open as usize;
+        result.drain(..finished).collect()
     }
 
-    result
+    // the inputs still waiting for the next event
+    pub fn finish(self) -> Vec<InputWithTimestamp> {
+        self.result
+    }
 }
 
+// relative events of one report arrive as separate lines with the same timestamp, combine them into one input
+fn push_or_merge(result: &mut Vec<InputWithTimestamp>, input: InputWithTimestamp) {
+    if let Some(last) = result.last_mut() {
+        if last.timestamp_milliseconds == input.timestamp_milliseconds && last.is_relative == input.is_relative && last.display_id == input.display_id && last.source == input.source {
+            match (&mut last.input, &input.input) {
+                (Input::Roll(last_roll), Input::Roll(roll)) => {
+                    last_roll.dx += roll.dx;
+                    last_roll.dy += roll.dy;
+                    return;
+                }
+                (Input::Scroll(last_scroll), Input::Scroll(scroll)) => {
+                    last_scroll.vertical += scroll.vertical;
+                    last_scroll.horizontal += scroll.horizontal;
+                    return;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    result.push(input);
+}
+
+
 
 
 
@@ -253,22 +1378,154 @@ pub fn convert_events_to_input(
 
 
 
+pub(crate) struct LineTiming {
+    // when the step runs, milliseconds since the start
+    pub(crate) time: u32,
+    // time of the previous step plus its wait
+    pub(crate) previous: u32,
+    // sum of all waits before the step, absolute times are shifted by it
+    pub(crate) shift: u32,
+}
+
+// the timeline the player, the exports and the linter share. An absolute time before the previous step runs right after it.
+// Documents aren't limited to MAX_SCRIPT_MS, so the sums saturate
+pub(crate) fn line_timings(lines: &[ScriptLine]) -> Vec<LineTiming> {
+    let mut previous: u32 = 0;
+    let mut shift: u32 = 0;
+
+    lines
+        .iter()
+        .map(|line| {
+            let time = match line.timing() {
+                Some((ms, true)) => previous.saturating_add(ms),
+                Some((ms, false)) => ms.saturating_add(shift).max(previous),
+                None => previous,
+            };
+            let timing = LineTiming { time, previous, shift };
+
+            previous = time;
+            if let Some(InputWithTimestamp { input: Input::Wait(ms), .. }) = line.as_input() {
+                previous = previous.saturating_add(*ms);
+                shift = shift.saturating_add(*ms);
+            }
+            timing
+        })
+        .collect()
+}
+
+// the time each line runs at in milliseconds since the start, relative delays and waits resolved
+pub fn resolve_timeline(lines: &[ScriptLine]) -> Vec<u32> {
+    line_timings(lines).iter().map(|t| t.time).collect()
+}
 
-pub fn serialize_inputs<T: std::io::Write>(inputs: &[InputWithTimestamp], writer: &mut T) {
+// rewrites every step as delay after the previous one, the timeline stays the same
+pub fn to_relative_timing(lines: &mut [ScriptLine]) {
+    let timings = line_timings(lines);
+    for (line, timing) in lines.iter_mut().zip(timings) {
+        if let Some((timestamp_milliseconds, is_relative)) = line.timing_mut() {
+            *timestamp_milliseconds = timing.time - timing.previous;
+            *is_relative = true;
+        }
+    }
+}
+
+// rewrites every step as time since the start, the timeline stays the same. Waits are kept and still shift what follows
+pub fn to_absolute_timing(lines: &mut [ScriptLine]) {
+    let timings = line_timings(lines);
+    for (line, timing) in lines.iter_mut().zip(timings) {
+        let is_wait = line.is_wait();
+        if let Some((timestamp_milliseconds, is_relative)) = line.timing_mut() {
+            if is_wait {
+                *timestamp_milliseconds = timing.time.saturating_sub(timing.previous);
+                *is_relative = true;
+            } else {
+                *timestamp_milliseconds = timing.time - timing.shift;
+                *is_relative = false;
+            }
+        }
+    }
+}
+
+// keeps the lines selected returns true for, the others become blank lines so the indices stay the same.
+// Every kept step waits its own delay after the line before it and the first one runs without delay.
+// A "repeat" and its "}" are only kept together, the selected lines of a block without them run once.
+// Labels are always kept, so gotos still find them
+pub fn select_lines(lines: &mut [ScriptLine], selected: impl Fn(usize) -> bool) {
+    to_relative_timing(lines);
+
+    let mut kept: Vec<bool> = (0..lines.len()).map(|idx| selected(idx) || matches!(lines[idx], ScriptLine::Label(_))).collect();
+    let mut open_repeats = Vec::new();
+    for (idx, line) in lines.iter().enumerate() {
+        match line {
+            ScriptLine::RepeatStart(_) => open_repeats.push(idx),
+            ScriptLine::RepeatEnd => {
+                if let Some(start) = open_repeats.pop() {
+                    let keep_block = kept[start] && kept[idx];
+                    kept[start] = keep_block;
+                    kept[idx] = keep_block;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut is_first = true;
+    for (line, kept) in lines.iter_mut().zip(kept) {
+        if !kept {
+            *line = ScriptLine::Blank;
+        } else if let Some((timestamp_milliseconds, _)) = line.timing_mut() {
+            if is_first {
+                *timestamp_milliseconds = 0;
+                is_first = false;
+            }
+        }
+    }
+}
+
+// replaces pixel coordinates with fractions of the display each input is sent to
+pub fn normalize_inputs(inputs: &mut [InputWithTimestamp], screen_infos: &ScreenInfos) {
     for i in inputs {
+        let display_size = screen_infos.display(i.display_id).display_size();
+        i.input.normalize(display_size);
+    }
+}
+
+// the lines as written to a file, indented by two spaces per repeat block they are in
+pub fn indented_lines(lines: &[ScriptLine]) -> Vec<String> {
+    let mut depth: usize = 0;
+    lines
+        .iter()
+        .map(|line| {
+            if let ScriptLine::RepeatEnd = line {
+                depth = depth.saturating_sub(1);
+            }
+            let text = match line {
+                ScriptLine::Blank => String::new(),
+                line => format!("{}{}", "  ".repeat(depth), line),
+            };
+            if let ScriptLine::RepeatStart(_) = line {
+                depth += 1;
+            }
+            text
+        })
+        .collect()
+}
+
+pub fn serialize_inputs<T: std::io::Write>(inputs: &[ScriptLine], writer: &mut T) {
+    for i in indented_lines(inputs) {
         writeln!(writer, "{i}").expect("should not fail");
     }
 }
 
-pub fn serialize_inputs_fmt<T: std::fmt::Write>(inputs: &[InputWithTimestamp], writer: &mut T) {
-    for i in inputs {
+pub fn serialize_inputs_fmt<T: std::fmt::Write>(inputs: &[ScriptLine], writer: &mut T) {
+    for i in indented_lines(inputs) {
         writeln!(writer, "{i}").expect("should not fail");
     }
 }
 
 pub fn deser_inputs_fmt<T: std::io::BufRead>(
     reader: &mut T,
-) -> Result<Vec<InputWithTimestamp>, ()> {
+) -> Result<Vec<ScriptLine>, ()> {
     let mut res = Vec::new();
     let mut linebuf = String::new();
     loop {
@@ -288,14 +1545,101 @@ pub fn deser_inputs_fmt<T: std::io::BufRead>(
 pub struct InputStrings(pub Vec<String>);
 
 impl InputStrings{
-    pub fn from_inputs(inputs: &[InputWithTimestamp]) -> Self 
+    pub fn from_inputs(inputs: &[ScriptLine]) -> Self 
     {
         let mut res = Vec::new();
 
-        for (index, input) in inputs.iter().enumerate() {
+        for (index, input) in indented_lines(inputs).iter().enumerate() {
             res.push(format!("{:3} {}", index, input)); 
         }
         Self(res)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_lines(text: &str) -> Vec<ScriptLine> {
+        text.lines().map(|line| line.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn times_beyond_the_limit_are_rejected() {
+        assert!("4294967295 tap 1 1".parse::<ScriptLine>().is_err());
+        assert!("+4294967295ms tap 1 1".parse::<ScriptLine>().is_err());
+        assert!("wait 4294967295".parse::<ScriptLine>().is_err());
+        assert!("1000000000 tap 1 1".parse::<ScriptLine>().is_ok());
+    }
+
+    #[test]
+    fn timeline_saturates() {
+        let mut lines = parse_lines("0 tap 1 1\nwait 5");
+        if let Some((ms, _)) = lines[0].timing_mut() {
+            *ms = u32::MAX;
+        }
+        assert_eq!(resolve_timeline(&lines), vec![u32::MAX, u32::MAX]);
+    }
+
+    #[test]
+    fn unknown_device_sources_are_skipped() {
+        let known = InputSource::parse_device_sources("KEYBOARD | TOUCHSCREEN").unwrap();
+        let with_unknown = InputSource::parse_device_sources("KEYBOARD | VENDOR_THING | TOUCHSCREEN").unwrap();
+        assert_eq!(known, with_unknown);
+        assert!(InputSource::Touchscreen.is_in(with_unknown));
+        assert_eq!(InputSource::parse_device_sources("0x00001103"), Some(0x1103));
+    }
+
+    #[test]
+    fn older_source_layouts_are_read() {
+        let input: InputWithTimestamp = "0 -d 1 mouse tap 1 1".parse().unwrap();
+        assert!(matches!((input.source, input.display_id), (Some(InputSource::Mouse), Some(1))));
+        let input: InputWithTimestamp = "0 mouse -d 1 tap 1 1".parse().unwrap();
+        let written: InputWithTimestamp = input.to_string().parse().unwrap();
+        assert!(matches!((written.source, written.display_id), (Some(InputSource::Mouse), Some(1))));
+    }
+
+    #[test]
+    fn writing_indents_repeat_blocks() {
+        let text = "# setup\n     0 tap       1    1\n\nrepeat 2 {\n  repeat 3 {\n    +10ms tap       2    2\n  }\n}\n";
+        let mut lines = Vec::new();
+        deser_inputs_fmt(&mut text.as_bytes()).map(|l| lines = l).unwrap();
+        let mut written = String::new();
+        serialize_inputs_fmt(&lines, &mut written);
+        assert_eq!(written, text);
+    }
+
+    #[test]
+    fn set_needs_a_readable_name() {
+        assert!("set a_1 = 5".parse::<ScriptLine>().is_ok());
+        assert!("set a-b = 5".parse::<ScriptLine>().is_err());
+        assert!("set  = 5".parse::<ScriptLine>().is_err());
+    }
+
+    #[test]
+    fn selection_inside_a_repeat_block_plays() {
+        let mut lines = parse_lines("0 tap 1 1\nrepeat 2 {\n+10ms tap 2 2\n+10ms tap 3 3\n}\n+10ms tap 4 4");
+        select_lines(&mut lines, |idx| idx >= 3);
+        assert!(crate::control_flow::ControlFlow::new(&lines).is_ok());
+        assert!(matches!(lines[1], ScriptLine::Blank));
+        assert!(matches!(lines[4], ScriptLine::Blank));
+        assert_eq!(resolve_timeline(&lines)[3..], [0, 0, 10]);
+    }
+
+    #[test]
+    fn selected_repeat_block_is_kept() {
+        let mut lines = parse_lines("0 tap 1 1\nrepeat 2 {\n+10ms tap 2 2\n}\nlabel end");
+        select_lines(&mut lines, |idx| (1..=3).contains(&idx));
+        assert!(matches!(lines[0], ScriptLine::Blank));
+        assert!(matches!(lines[1], ScriptLine::RepeatStart(2)));
+        assert!(matches!(lines[3], ScriptLine::RepeatEnd));
+        assert!(matches!(lines[4], ScriptLine::Label(_)));
+    }
+
+    #[test]
+    fn backward_absolute_time_runs_after_previous() {
+        let lines = parse_lines("1000 tap 1 1\n500 tap 2 2\n+50ms tap 3 3");
+        assert_eq!(resolve_timeline(&lines), vec![1000, 1000, 1050]);
+    }
+}
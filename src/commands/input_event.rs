@@ -25,8 +25,27 @@ pub enum InputEvent {
     AbsMtPosX(i32),
     AbsMtPosY(i32),
     KeyPower(TouchType),
+    BtnLeft(TouchType),
+    BtnRight(TouchType),
+    RelX(i32),
+    RelY(i32),
+    RelWheel(i32),
+    RelHWheel(i32),
+    AbsMtToolType(i32),
+    AbsMtPressure(i32),
+    AbsX(i32),
+    AbsY(i32),
+    AbsPressure(i32),
+    AbsTiltX(i32),
+    AbsTiltY(i32),
+    BtnToolPen(TouchType),
+    BtnStylus(TouchType),
+    SynReport,
 }
 
+// ABS_MT_TOOL_TYPE value reported for a pen
+pub const MT_TOOL_PEN: i32 = 1;
+
 impl Display for InputEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -36,6 +55,22 @@ impl Display for InputEvent {
             InputEvent::AbsMtPosX(d) => write!(f, "PosX({})", d),
             InputEvent::AbsMtPosY(d) => write!(f, "PosY({})", d),
             InputEvent::KeyPower(t) => write!(f, "Power ({})", t),
+            InputEvent::BtnLeft(t) => write!(f, "Left ({})", t),
+            InputEvent::BtnRight(t) => write!(f, "Right ({})", t),
+            InputEvent::RelX(d) => write!(f, "RelX({})", d),
+            InputEvent::RelY(d) => write!(f, "RelY({})", d),
+            InputEvent::RelWheel(d) => write!(f, "Wheel({})", d),
+            InputEvent::RelHWheel(d) => write!(f, "HWheel({})", d),
+            InputEvent::AbsMtToolType(i) => write!(f, "ToolType({})", i),
+            InputEvent::AbsMtPressure(d) => write!(f, "MtPressure({})", d),
+            InputEvent::AbsX(d) => write!(f, "AbsX({})", d),
+            InputEvent::AbsY(d) => write!(f, "AbsY({})", d),
+            InputEvent::AbsPressure(d) => write!(f, "Pressure({})", d),
+            InputEvent::AbsTiltX(d) => write!(f, "TiltX({})", d),
+            InputEvent::AbsTiltY(d) => write!(f, "TiltY({})", d),
+            InputEvent::BtnToolPen(t) => write!(f, "Pen ({})", t),
+            InputEvent::BtnStylus(t) => write!(f, "Stylus ({})", t),
+            InputEvent::SynReport => write!(f, "SynReport"),
         }
     }
 }
@@ -115,6 +115,8 @@ fn parse_input_event_name(
     // EV_ABS       ABS_MT_SLOT          00000000
     // EV_ABS       ABS_MT_TRACKING_ID   0000013e
     // EV_ABS       ABS_MT_POSITION_X    000001de
+    // EV_KEY       BTN_MOUSE            DOWN
+    // EV_REL       REL_WHEEL            ffffffff
     let parsed = match ev_sub_type {
         "BTN_TOUCH" => Some(InputEvent::BtnTouch(TouchType::from_str(ev_value)?)),
         "ABS_MT_TRACKING_ID" => Some(InputEvent::AbsMtTrackingId(parse_hex_i32(ev_value)?)),
@@ -122,6 +124,23 @@ fn parse_input_event_name(
         "ABS_MT_POSITION_X" => Some(InputEvent::AbsMtPosX(parse_hex_i32(ev_value)?)),
         "ABS_MT_POSITION_Y" => Some(InputEvent::AbsMtPosY(parse_hex_i32(ev_value)?)),
         "KEY_POWER" => Some(InputEvent::KeyPower(TouchType::from_str(ev_value)?)),
+        // BTN_LEFT and BTN_MOUSE share the same code, getevent may print either
+        "BTN_LEFT" | "BTN_MOUSE" => Some(InputEvent::BtnLeft(TouchType::from_str(ev_value)?)),
+        "BTN_RIGHT" => Some(InputEvent::BtnRight(TouchType::from_str(ev_value)?)),
+        "REL_X" => Some(InputEvent::RelX(parse_hex_i32(ev_value)?)),
+        "REL_Y" => Some(InputEvent::RelY(parse_hex_i32(ev_value)?)),
+        "REL_WHEEL" => Some(InputEvent::RelWheel(parse_hex_i32(ev_value)?)),
+        "REL_HWHEEL" => Some(InputEvent::RelHWheel(parse_hex_i32(ev_value)?)),
+        "ABS_MT_TOOL_TYPE" => Some(InputEvent::AbsMtToolType(parse_hex_i32(ev_value)?)),
+        "ABS_MT_PRESSURE" => Some(InputEvent::AbsMtPressure(parse_hex_i32(ev_value)?)),
+        "ABS_X" => Some(InputEvent::AbsX(parse_hex_i32(ev_value)?)),
+        "ABS_Y" => Some(InputEvent::AbsY(parse_hex_i32(ev_value)?)),
+        "ABS_PRESSURE" => Some(InputEvent::AbsPressure(parse_hex_i32(ev_value)?)),
+        "ABS_TILT_X" => Some(InputEvent::AbsTiltX(parse_hex_i32(ev_value)?)),
+        "ABS_TILT_Y" => Some(InputEvent::AbsTiltY(parse_hex_i32(ev_value)?)),
+        "BTN_TOOL_PEN" => Some(InputEvent::BtnToolPen(TouchType::from_str(ev_value)?)),
+        "BTN_STYLUS" => Some(InputEvent::BtnStylus(TouchType::from_str(ev_value)?)),
+        "SYN_REPORT" => Some(InputEvent::SynReport),
         _ => {
             // println!("not implemented: {} {} {}", _ev_type, ev_sub_type, ev_value);
             None
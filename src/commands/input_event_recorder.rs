@@ -14,9 +14,10 @@ use tokio::{
 
 use crate::{
     device_entry::DeviceEntry,
-    input::{InputWithTimestamp, convert_events_to_input},
+    event_log::{EventLog, EventLogParser},
+    input::{normalize_inputs, DisplayMetrics, EventConverter, InputSource, InputWithTimestamp, ScriptLine},
     input_event::InputEventInfo,
-    input_event_parser::ParsedGetEventOutput,
+    script::{ConversionSettings, DeviceInfo, Script, ScriptHeader},
 };
 
 use super::NO_WINDOW_FLAGS;
@@ -40,16 +41,18 @@ pub enum ReadEventsError {
     ParseError,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub enum StatusMessage {
     RecordedInput(InputEventInfo),
+    // a gesture or key press finished while recording. The script is converted again from all events after stopping
+    ConvertedInput(InputWithTimestamp),
     RecordingFinished,
 }
 
 pub struct InputRecorder {
     status_recv: mpsc::UnboundedReceiver<StatusMessage>,
     process_kill_send: Option<oneshot::Sender<()>>,
-    result_recv: Option<oneshot::Receiver<Option<Vec<InputWithTimestamp>>>>,
+    result_recv: Option<oneshot::Receiver<Option<(Script, EventLog)>>>,
 }
 
 impl InputRecorder {
@@ -57,9 +60,10 @@ impl InputRecorder {
         gui_context: &egui::Context,     
         tap_threshold_distance : u32,
         tap_threshold_ms : u32,
+        normalize_coordinates : bool,
     ) -> Self {
         let (process_kill_send, process_kill_recv) = oneshot::channel::<()>();
-        let (result_send, result_recv) = oneshot::channel::<Option<Vec<InputWithTimestamp>>>();
+        let (result_send, result_recv) = oneshot::channel::<Option<(Script, EventLog)>>();
         let (status_send, status_recv) = mpsc::unbounded_channel::<StatusMessage>();
 
         tokio::spawn(Self::start(
@@ -68,7 +72,8 @@ impl InputRecorder {
             result_send,
             process_kill_recv,
             tap_threshold_distance,
-            tap_threshold_ms
+            tap_threshold_ms,
+            normalize_coordinates,
         ));
 
         Self {
@@ -98,7 +103,8 @@ impl InputRecorder {
         }
     }
 
-    pub fn try_get_result(&mut self) -> Result<Vec<InputWithTimestamp>, GetResultError> {
+    // the converted script and the raw log it was converted from
+    pub fn try_get_result(&mut self) -> Result<(Script, EventLog), GetResultError> {
         if let Some(recv) = &mut self.result_recv {
             let res = recv.try_recv().map_err(|err| match err {
                 oneshot::error::TryRecvError::Empty => GetResultError::NotYetAvailable,
@@ -117,10 +123,11 @@ impl InputRecorder {
     async fn start(
         gui_context: egui::Context,
         status_send: mpsc::UnboundedSender<StatusMessage>,
-        result_send: oneshot::Sender<Option<Vec<InputWithTimestamp>>>,
+        result_send: oneshot::Sender<Option<(Script, EventLog)>>,
         terminate: oneshot::Receiver<()>,
         tap_threshold_distance : u32,
         tap_threshold_ms : u32,
+        normalize_coordinates : bool,
     ) {
 
         
@@ -161,10 +168,16 @@ impl InputRecorder {
             }
         });
 
+        // asked while getevent already runs, its output waits in the pipe
+        let screen_infos = get_screen_info().await.ok_or(ReadEventsError::ParseError).unwrap_or_default();
+        let conversion = ConversionSettings { tap_threshold_distance, tap_threshold_ms, normalize_coordinates };
+
         let join_handle_read_input = tokio::spawn(record_inputs_output(
             child_output,
             gui_context.clone(),
             status_send.clone(),
+            conversion,
+            screen_infos.clone(),
         ));
 
         if let Err(err) = terminate.await {
@@ -179,7 +192,7 @@ impl InputRecorder {
             println!("join err, err:{}", err.to_string());
         }
 
-        let device_entry_and_input_events = match join_handle_read_input.await {
+        let event_log = match join_handle_read_input.await {
             Err(err) => {
                 eprintln!("failed joining {}", err.to_string());
                 None
@@ -187,10 +200,19 @@ impl InputRecorder {
             Ok(ok) => ok.ok(),
         };
 
-        let screen_info = get_screen_info().await.ok_or(ReadEventsError::ParseError).unwrap_or(Default::default());
+        let device_info = get_device_info().await;
+
+        let inputs = event_log.map(|log| {
+            let inputs = log.convert(conversion, &screen_infos);
 
-        let inputs = device_entry_and_input_events.map(
-            |e| convert_events_to_input(&e.1, tap_threshold_distance, tap_threshold_ms, screen_info));
+            let mut header = ScriptHeader::new(device_info, Some(screen_infos.default_display()), conversion);
+            header.screen_infos = Some(screen_infos);
+            let script = Script {
+                header,
+                lines: inputs.into_iter().map(ScriptLine::Input).collect(),
+            };
+            (script, log)
+        });
 
         if let Err(_) = result_send.send(inputs) {
             eprintln!("failed to send result");
@@ -213,18 +235,16 @@ async fn record_inputs_output(
     stdout: ChildStdout,
     gui_context: egui::Context,
     status_sender: mpsc::UnboundedSender<StatusMessage>,
-) -> Result<(Vec<DeviceEntry>, Vec<InputEventInfo>), ReadEventsError> {
+    conversion: ConversionSettings,
+    screen_infos: ScreenInfos,
+) -> Result<EventLog, ReadEventsError> {
     let mut stdout_reader = BufReader::new(stdout);
 
-
-
-
-
     let mut line_buffer = String::new();
 
-    let mut last_device_with_event = None;
-    let mut devices = Vec::new();
-    let mut inputs = Vec::new();
+    let mut parser = EventLogParser::default();
+    let mut converter = EventConverter::new(conversion.tap_threshold_distance, conversion.tap_threshold_ms, screen_infos.clone());
+    let mut log = EventLog::default();
 
     loop {
         line_buffer.clear();
@@ -237,101 +257,282 @@ async fn record_inputs_output(
             break;
         }
 
-        let maybe_parsed = ParsedGetEventOutput::try_from_str(&line_buffer)
-            .ok()
-            .flatten();
-
-        if let Some(parsed) = maybe_parsed {
-            match parsed {
-                ParsedGetEventOutput::AddDevice(dwe) => last_device_with_event = Some(dwe),
-                ParsedGetEventOutput::Name(name) => {
-                    if let Some(dwe) = last_device_with_event.take() {
-                        devices.push(DeviceEntry {
-                            device_nr: dwe.device_nr,
-                            event_nr: dwe.event_nr,
-                            name: name.to_string(),
-                        });
-                    } else {
-                        eprintln!("warning: igorning name no dwe present");
-                    }
-                }
+        // the log is kept as adb printed it, without the \r adb adds on windows
+        log.text += line_buffer.trim_end();
+        log.text.push('\n');
 
-                ParsedGetEventOutput::Input(input) => {
-                    inputs.push(input);
-                    if let Err(_) = status_sender.send(StatusMessage::RecordedInput(input)) {
-                        println!("receiver dropped, stopping parsing");
-                        break;
-                    }
-                    gui_context.request_repaint();
-                }
-            };
+        if let Some(input) = parser.push_line(&line_buffer) {
+            let mut converted = converter.push(&input, &parser.devices);
+            if conversion.normalize_coordinates {
+                normalize_inputs(&mut converted, &screen_infos);
+            }
+
+            let messages = std::iter::once(StatusMessage::RecordedInput(input))
+                .chain(converted.into_iter().map(StatusMessage::ConvertedInput));
+            if messages.map(|m| status_sender.send(m)).any(|r| r.is_err()) {
+                println!("receiver dropped, stopping parsing");
+                break;
+            }
+            gui_context.request_repaint();
         }
     }
 
-    Ok((devices, inputs))
+    Ok(log)
 }
 
 
-async fn get_screen_info() -> Option<ScreenInfo> {
-    let output = Command::new("adb.exe")
-    .arg("shell")
-    .arg("dumpsys")
-    .arg("input")
+pub async fn get_screen_info() -> Option<ScreenInfos> {
+    let displays = get_display_screen_infos(None).await?;
+    let input_devices = get_input_device_infos().await?;
+
+    Some(ScreenInfos {
+        displays,
+        input_devices,
+    })
+}
+
+pub async fn get_device_info() -> Option<DeviceInfo> {
+    let model = run_adb(&["shell", "getprop", "ro.product.model"]).await?;
+    let serial = run_adb(&["get-serialno"]).await?;
+
+    Some(DeviceInfo {
+        model: model.trim().to_string(),
+        serial: serial.trim().to_string(),
+    })
+}
+
+// size and density of a display in its current orientation, `wm` reports an override if one is set after the physical values
+pub async fn get_display_metrics(serial: Option<&str>, display_id: Option<i32>) -> Option<DisplayMetrics> {
+    let display_id_str = display_id.map(|id| id.to_string());
+    let with_display = |args: &[&'static str]| {
+        let mut args: Vec<&str> = args.to_vec();
+        if let Some(id) = &display_id_str {
+            args.push("-d");
+            args.push(id);
+        }
+        args
+    };
+
+    // Physical size: 1080x2400
+    let size = run_adb_on(serial, &with_display(&["shell", "wm", "size"])).await?;
+    let (width, height) = size
+        .lines()
+        .rev()
+        .find_map(|l| l.split_once("size:"))?
+        .1
+        .trim()
+        .split_once('x')?;
+    let width: i32 = width.parse().ok()?;
+    let height: i32 = height.parse().ok()?;
+
+    // Physical density: 420
+    let density = run_adb_on(serial, &with_display(&["shell", "wm", "density"])).await?;
+    let density = density
+        .lines()
+        .rev()
+        .find_map(|l| l.split_once("density:"))?
+        .1
+        .trim()
+        .parse()
+        .ok()?;
+
+    let orientation = get_display_screen_infos(serial)
+        .await
+        .and_then(|displays| displays.into_iter().find(|d| d.display_id == display_id.unwrap_or(0)))
+        .map(|d| d.orientation)
+        .unwrap_or(Orientation::Portrait);
+
+    let (width, height) = match orientation {
+        Orientation::Portrait => (width, height),
+        Orientation::LandscapeLeft | Orientation::LandscapeRight => (height, width),
+    };
+
+    Some(DisplayMetrics {
+        width,
+        height,
+        density,
+    })
+}
+
+// serial numbers of the devices adb can send to, unauthorized and offline devices are left out
+pub async fn get_connected_devices() -> Option<Vec<String>> {
+    let s = run_adb(&["devices"]).await?;
+
+    // List of devices attached
+    // R58N12ABCDE	device
+    // emulator-5554	offline
+    Some(
+        s.lines()
+            .skip(1)
+            .filter_map(|l| l.split_once('\t'))
+            .filter(|(_, state)| state.trim() == "device")
+            .map(|(serial, _)| serial.to_string())
+            .collect(),
+    )
+}
+
+async fn run_adb(args: &[&str]) -> Option<String> {
+    run_adb_on(None, args).await
+}
+
+// runs on the device with the serial, None for the only connected one
+async fn run_adb_on(serial: Option<&str>, args: &[&str]) -> Option<String> {
+    let mut command = Command::new("adb.exe");
+    if let Some(serial) = serial {
+        command.arg("-s").arg(serial);
+    }
+    let output = command
+    .args(args)
     .creation_flags(NO_WINDOW_FLAGS)
     .output()
     .await
     .ok()?;
 
-    let s =  str::from_utf8(&output.stdout).ok()?;
+    String::from_utf8(output.stdout).ok()
+}
 
+async fn run_dumpsys(serial: Option<&str>, service: &str) -> Option<String> {
+    run_adb_on(serial, &["shell", "dumpsys", service]).await
+}
 
-    let mut iter = s.split_ascii_whitespace();
+// value of "key=value" pairs as used by the dumpsys outputs, the value ends at ',' '}' or whitespace
+fn find_key_value<'a>(s: &'a str, key: &str) -> Option<&'a str> {
+    let start = s.find(key)? + key.len();
+    let value = s[start..].strip_prefix('=')?;
+    let end = value
+        .find(|c: char| c == ',' || c == '}' || c.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    Some(&value[..end])
+}
 
+fn parse_orientation(s: &str) -> Option<Orientation> {
+    match s.parse::<u32>().ok()? {
+        0 => Some(Orientation::Portrait),
+        1 => Some(Orientation::LandscapeLeft),
+        3 => Some(Orientation::LandscapeRight),
+        _ => None
+    }
+}
 
+async fn get_display_screen_infos(serial: Option<&str>) -> Option<Vec<ScreenInfo>> {
+    let s = run_dumpsys(serial, "display").await?;
 
-    let mut get_next_param = move |s|{
-        loop {
-            if s == iter.next()?
-            {
-                break iter.next()
-            }            
+    // mViewports=[DisplayViewport{type=INTERNAL, valid=true, isActive=true, displayId=0, uniqueId='local:4619827259835644672',
+    // physicalPort=0, orientation=0, logicalFrame=Rect(0, 0 - 1080, 2400), physicalFrame=Rect(0, 0 - 1080, 2400), deviceWidth=1080, deviceHeight=2400}]
+    let mut displays: Vec<ScreenInfo> = Vec::new();
+    for viewport in s.split("DisplayViewport{").skip(1) {
+        let viewport = viewport.split('}').next()?;
+
+        let display_id = match find_key_value(viewport, "displayId").and_then(|v| v.parse().ok()) {
+            Some(id) => id,
+            None => continue,
+        };
+        let orientation = match find_key_value(viewport, "orientation").and_then(parse_orientation) {
+            Some(o) => o,
+            None => continue,
+        };
+        let device_width = find_key_value(viewport, "deviceWidth").and_then(|v| v.parse().ok());
+        let device_height = find_key_value(viewport, "deviceHeight").and_then(|v| v.parse().ok());
+        let (device_width, device_height) = match (device_width, device_height) {
+            (Some(w), Some(h)) => (w, h),
+            _ => continue,
+        };
+
+        // the viewport size is rotated, the screen info holds the size in natural orientation
+        let (width, height) = match orientation {
+            Orientation::Portrait => (device_width, device_height),
+            Orientation::LandscapeLeft | Orientation::LandscapeRight => (device_height, device_width),
+        };
+
+        if !displays.iter().any(|d| d.display_id == display_id) {
+            displays.push(ScreenInfo {
+                display_id,
+                orientation,
+                width,
+                height,
+            });
         }
-    };
+    }
 
+    Some(displays)
+}
 
+async fn get_input_device_infos() -> Option<Vec<InputDeviceInfo>> {
+    let s = run_dumpsys(None, "input").await?;
 
-    let width = get_next_param("RawSurfaceWidth:")?
-        .trim_end_matches("px")
-        .parse::<i32>()
-        .expect("RawSurfaceWidth: not found or parsed");
+    struct Touchscreen {
+        display_id: i32,
+        width: Option<i32>,
+        height: Option<i32>,
+        orientation: Option<Orientation>,
+    }
 
-    let height = get_next_param("RawSurfaceHeight:")?
-        .trim_end_matches("px")
-        .parse::<i32>()
-        .expect("RawSurfaceHeight: not found or parsed");
+    let mut devices = Vec::new();
+    let mut current: Option<(InputDeviceInfo, Touchscreen)> = None;
+
+    let finish = |(mut device, t): (InputDeviceInfo, Touchscreen)| {
+        if let Touchscreen { display_id, width: Some(width), height: Some(height), orientation: Some(orientation) } = t {
+            device.screen_info = Some(ScreenInfo {
+                display_id,
+                orientation,
+                width,
+                height,
+            });
+        }
+        device
+    };
 
-    let orientation = get_next_param("SurfaceOrientation:")?
-        .parse::<u32>()
-        .ok()
-        .map(|i| match i {
-            0 => Some(Orientation::Portrait),
-            1 => Some(Orientation::LandscapeLeft),
-            3 => Some(Orientation::LandscapeRight),
-            _ => None
-        })  
-        .flatten()
-        .expect("SurfaceOrientation: not found or parsed");
+    //   Device 5: sec_touchscreen
+    //     ...
+    //     Sources: 0x00005103
+    //     ...
+    //       Viewport INTERNAL: displayId=0, uniqueId=local:4619827259835644672, port=0, orientation=0, ...
+    //       RawSurfaceWidth: 1080px
+    //       RawSurfaceHeight: 2400px
+    //       SurfaceOrientation: 0
+    for line in s.lines().map(str::trim) {
+        let device_name = line
+            .strip_prefix("Device ")
+            .and_then(|rest| rest.split_once(": "))
+            .filter(|(nr, _)| nr.parse::<i32>().is_ok())
+            .map(|(_, name)| name);
+
+        if let Some(name) = device_name {
+            devices.extend(current.take().map(finish));
+            current = Some((
+                InputDeviceInfo { name: name.to_string(), sources: 0, screen_info: None },
+                Touchscreen { display_id: 0, width: None, height: None, orientation: None },
+            ));
+            continue;
+        }
 
+        let (device, t) = match current.as_mut() {
+            Some(c) => c,
+            None => continue,
+        };
 
+        if let Some(v) = line.strip_prefix("Sources:") {
+            device.sources = InputSource::parse_device_sources(v).unwrap_or(0);
+        } else if line.starts_with("Viewport") {
+            if let Some(display_id) = find_key_value(line, "displayId").and_then(|v| v.parse().ok()) {
+                t.display_id = display_id;
+            }
+        } else if let Some(v) = line.strip_prefix("RawSurfaceWidth:") {
+            t.width = v.trim().trim_end_matches("px").parse().ok();
+        } else if let Some(v) = line.strip_prefix("RawSurfaceHeight:") {
+            t.height = v.trim().trim_end_matches("px").parse().ok();
+        } else if let Some(v) = line.strip_prefix("SurfaceOrientation:") {
+            t.orientation = parse_orientation(v.trim());
+        }
+    }
+    devices.extend(current.take().map(finish));
 
-    Some(ScreenInfo {
-        height,
-        orientation,
-        width
-        })
+    Some(devices)
 }
 
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
 pub struct ScreenInfo {
+    pub display_id : i32,
     pub orientation : Orientation,
     pub width : i32,
     pub height : i32,
@@ -347,16 +548,85 @@ impl ScreenInfo {
         }
     }
 
+    // width and height as seen on the display after remapping
+    pub fn display_size(&self) -> (i32,i32)
+    {
+        match self.orientation {
+            Orientation::Portrait => (self.width, self.height),
+            Orientation::LandscapeLeft | Orientation::LandscapeRight => (self.height, self.width),
+        }
+    }
+
 }
 
 impl Default for ScreenInfo {
     fn default() -> Self {
-        Self { orientation: Orientation::Portrait, width: 0, height: 0}
+        Self { display_id: 0, orientation: Orientation::Portrait, width: 0, height: 0}
     }
 }
 
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
 pub enum Orientation {
     Portrait,
     LandscapeLeft,
     LandscapeRight
 }
+
+// an input device as listed by `dumpsys input`
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct InputDeviceInfo {
+    pub name : String,
+    // AINPUT_SOURCE_* flags
+    pub sources : u32,
+    // the screen a touchscreen is associated with
+    pub screen_info : Option<ScreenInfo>,
+}
+
+#[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct ScreenInfos {
+    pub displays : Vec<ScreenInfo>,
+    pub input_devices : Vec<InputDeviceInfo>,
+}
+
+impl ScreenInfos {
+    pub fn default_display(&self) -> ScreenInfo {
+        self.displays
+            .iter()
+            .find(|d| d.display_id == 0)
+            .or_else(|| self.displays.first())
+            .copied()
+            .or_else(|| self.input_devices.iter().find_map(|d| d.screen_info))
+            .unwrap_or_default()
+    }
+
+    // the screen the events of a device map to, devices which are not a touchscreen use the default display
+    pub fn for_device(&self, devices: &[DeviceEntry], event_nr: i32) -> ScreenInfo {
+        self.input_device(devices, event_nr)
+            .and_then(|d| d.screen_info)
+            .unwrap_or_else(|| self.default_display())
+    }
+
+    // AINPUT_SOURCE_* flags of a device, 0 if it is unknown
+    pub fn sources_for_device(&self, devices: &[DeviceEntry], event_nr: i32) -> u32 {
+        self.input_device(devices, event_nr)
+            .map(|d| d.sources)
+            .unwrap_or(0)
+    }
+
+    // getevent and dumpsys number the devices differently, so they are matched by name
+    fn input_device(&self, devices: &[DeviceEntry], event_nr: i32) -> Option<&InputDeviceInfo> {
+        let device = devices.iter().find(|d| d.event_nr == event_nr)?;
+        self.input_devices.iter().find(|d| d.name == device.name)
+    }
+
+    pub fn display(&self, display_id: Option<i32>) -> ScreenInfo {
+        display_id
+            .and_then(|id| self.displays.iter().find(|d| d.display_id == id))
+            .copied()
+            .unwrap_or_else(|| self.default_display())
+    }
+
+    pub fn has_multiple_displays(&self) -> bool {
+        self.displays.len() > 1
+    }
+}
@@ -1,98 +1,743 @@
-use std::{fmt::Write, process::Stdio, sync::Arc, time::Duration};
+use std::{fmt::{self, Display, Formatter}, process::{Output, Stdio}, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime}};
 
 use tokio::{
     process::Command,
-    sync::{oneshot::{self, error::TryRecvError}, watch},
+    sync::{mpsc, oneshot::{self, error::TryRecvError}, watch, Notify},
 };
 
-use crate::input::{InputWithTimestamp, Input};
+use crate::{
+    control_flow::{ControlFlow, ControlFlowError},
+    expression::{evaluate, Rng, Variables},
+    input::{resolve_timeline, DisplayMetrics, InputWithTimestamp, Input, ScriptLine, Stroke},
+    export::quote,
+    input_event_recorder::{get_display_metrics, Orientation, ScreenInfos},
+};
 
 use super::NO_WINDOW_FLAGS;
 
+#[derive(Clone, Copy)]
+pub enum ReadNextResultError {
+    Empty,
+    Finished,
+}
+
+// lines a dry run goes through at most, over all repetitions
+const MAX_DRY_RUN_LINES: u32 = 100_000;
+
 pub struct InputPlayer {
     stop_send: Option<oneshot::Sender<()>>,
     status_recv: watch::Receiver<InputReplayState>,
+    speed_send: watch::Sender<PlaybackSpeed>,
+    command_send: mpsc::UnboundedSender<PlayerCommand>,
+    result_recv: mpsc::UnboundedReceiver<StepResult>,
+    log_recv: mpsc::UnboundedReceiver<LoggedCommand>,
+    metrics_recv: watch::Receiver<Vec<(Option<i32>, DisplayMetrics)>>,
+}
+
+// a command a dry run would have sent
+#[derive(Clone, Debug)]
+pub struct LoggedCommand {
+    // index of the line
+    pub line : usize,
+    pub repetition : u32,
+    // when it would have been sent, since playing started
+    pub time : Duration,
+    // the arguments of adb
+    pub args : Vec<String>,
+}
+
+impl Display for LoggedCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(|a| if a.contains(char::is_whitespace) { quote(a) } else { a.clone() }).collect();
+        write!(f, "{:9.3}s  adb {}", self.time.as_secs_f64(), args.join(" "))
+    }
+}
+
+// how sending a step went, inputs run in the background so results may arrive after later steps were sent
+#[derive(Clone, Debug)]
+pub struct StepResult {
+    // index of the line
+    pub line : usize,
+    pub repetition : u32,
+    // None if adb could not be run or was killed
+    pub exit_code : Option<i32>,
+    // why the step failed, None if it succeeded
+    pub error : Option<String>,
+    pub stderr : String,
+    // from sending the step until adb returned
+    pub latency : Duration,
+}
+
+impl StepResult {
+    // a line that failed before anything was sent, like an expression that can't be evaluated
+    fn failed(line: usize, repetition: u32, error: String) -> Self {
+        Self {
+            line,
+            repetition,
+            exit_code: None,
+            error: Some(error),
+            stderr: String::new(),
+            latency: Duration::ZERO,
+        }
+    }
+
+    fn new(line: usize, repetition: u32, input: &Input, output: std::io::Result<Output>, latency: Duration) -> Self {
+        let (exit_code, stderr) = match &output {
+            Ok(output) => (output.status.code(), String::from_utf8_lossy(&output.stderr).trim().to_string()),
+            Err(_) => (None, String::new()),
+        };
+        Self {
+            line,
+            repetition,
+            exit_code,
+            error: check_shell_output(input, output).err(),
+            stderr,
+            latency,
+        }
+    }
+}
+
+// the device a player sends to
+#[derive(Clone, Default)]
+pub struct PlayDevice {
+    // adb serial number, None for the only connected device
+    pub serial : Option<String>,
+    // the screens the script was recorded on, pixel coordinates are scaled from them to the displays of the device
+    pub recorded_screens : Option<ScreenInfos>,
+    // shared by players on other devices and the index of this one, every repetition starts when all are ready
+    pub barrier : Option<(Arc<StartBarrier>, usize)>,
+    // the commands are only logged with the time they would be sent at, without waiting for it
+    pub dry_run : bool,
+    // display sizes known before playing, the others are asked from the device
+    pub display_metrics : Vec<(Option<i32>, DisplayMetrics)>,
+    // nothing is asked from the device, a display size that is not given fails playing
+    pub offline : bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum DryRunError {
+    ControlFlow(ControlFlowError),
+    // a display size was not given, an expression failed or the script ran into the line limit, line is the index of the line
+    Failed { line: usize, message: String },
+}
+
+// keeps players on several devices at the same repetition, a player that stopped is not waited for
+pub struct StartBarrier {
+    // how many repetitions each player is ready to start, None once it stopped
+    arrived : Mutex<Vec<Option<u32>>>,
+    notify : Notify,
+}
+
+impl StartBarrier {
+    pub fn new(players: usize) -> Self {
+        Self {
+            arrived: Mutex::new(vec![Some(0); players]),
+            notify: Notify::new(),
+        }
+    }
+
+    async fn wait(&self, player: usize, repetition: u32) {
+        loop {
+            // created before checking, so a release in between is not missed
+            let notified = self.notify.notified();
+            {
+                let mut arrived = self.arrived.lock().unwrap_or_else(|e| e.into_inner());
+                arrived[player] = Some(repetition + 1);
+                if arrived.iter().flatten().all(|r| *r > repetition) {
+                    self.notify.notify_waiters();
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn leave(&self, player: usize) {
+        self.arrived.lock().unwrap_or_else(|e| e.into_inner())[player] = None;
+        self.notify.notify_waiters();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PlayerCommand {
+    // pauses before the next line
+    Pause,
+    Resume,
+    // runs the next input and pauses again
+    Step,
+    // line indices to pause at
+    SetBreakpoints(Vec<usize>),
 }
 
 #[derive(Clone, Copy, Debug)]
+pub struct PlaybackSpeed {
+    // 2.0 plays twice as fast, delays are divided by it
+    pub factor : f32,
+    // swipes and strokes take less time too, otherwise only the delays between steps are scaled
+    pub scale_swipes : bool,
+    // the last milliseconds before a step are waited actively, timers only wake up every few milliseconds
+    pub spin_wait : bool,
+}
+
+impl Default for PlaybackSpeed {
+    fn default() -> Self {
+        Self { factor: 1.0, scale_swipes: false, spin_wait: false }
+    }
+}
+
+// windows timers fire in steps of about 15.6 ms
+const SPIN_WAIT : Duration = Duration::from_millis(16);
+
+// when playing ends on its own, without any it repeats until stopped
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StopConditions {
+    // runs through the whole script
+    pub repetitions: Option<u32>,
+    // the duration and time stop in the middle of a run, before the next line
+    pub max_duration: Option<Duration>,
+    pub stop_at: Option<SystemTime>,
+    // a failing step is only reported, otherwise playing stops
+    pub continue_on_error: bool,
+}
+
+impl StopConditions {
+    // the earliest time playing has to stop, for a player started at start
+    fn deadline(&self, start: Instant) -> Option<(Instant, StopReason)> {
+        let duration = self.max_duration.map(|d| (start + d, StopReason::MaxDuration));
+        let time = self.stop_at.map(|t| {
+            let remaining = t.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO);
+            (start + remaining, StopReason::StopTime)
+        });
+        match (duration, time) {
+            (Some(d), Some(t)) => Some(if t.0 < d.0 { t } else { d }),
+            (d, t) => d.or(t),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Stopped,
+    Repetitions,
+    MaxDuration,
+    StopTime,
+}
+
+#[derive(Clone, Debug)]
 pub enum InputReplayState {
     NotStarted,
     Repeating(Repeating),
-    Finished
+    // waiting before reptetion_element for Resume or Step
+    Paused(Repeating),
+    // repetitions is the number of complete runs
+    Finished { repetitions: u32, reason: StopReason },
+    // a step failed and playing stopped, line is the index of the step
+    Failed { line: usize, message: String, repetitions: u32 },
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct Repeating {
     pub repetion : u32,
-    pub reptetion_element : Option<usize>
+    pub reptetion_element : Option<usize>,
+    // the repeat blocks currently running, outermost first
+    pub loops : Vec<LoopPosition>,
+    pub lateness : Lateness,
+}
+
+// how much later than scheduled the steps were sent since playing started
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lateness {
+    // of the last step sent
+    pub last : Duration,
+    pub max : Duration,
+    total : Duration,
+    steps : u32,
+}
+
+impl Lateness {
+    fn add(&mut self, late: Duration) {
+        self.last = late;
+        self.max = self.max.max(late);
+        self.total += late;
+        self.steps += 1;
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.steps {
+            0 => Duration::ZERO,
+            steps => self.total / steps,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LoopPosition {
+    // line index of the "repeat"
+    pub line : usize,
+    // starts at 0
+    pub iteration : u32,
+    pub count : u32,
 }
 
 impl InputPlayer {
-    pub fn new(gui_context: &egui::Context, inputs: Arc<Vec<InputWithTimestamp>>, delay_ms_between_loops : u32) -> Self {
+    pub fn new(gui_context: &egui::Context, inputs: Arc<Vec<ScriptLine>>, delay_ms_between_loops : u32, stop_conditions : StopConditions, seed : u64, speed : PlaybackSpeed, device : PlayDevice) -> Result<Self, ControlFlowError> {
+        let flow = ControlFlow::new(&inputs)?;
         let (stop_send, mut stop_recv) = oneshot::channel::<()>();
         let (status_send, status_recv) = watch::channel::<InputReplayState>(InputReplayState::NotStarted);
+        let (speed_send, mut speed_recv) = watch::channel::<PlaybackSpeed>(speed);
+        let (command_send, mut command_recv) = mpsc::unbounded_channel::<PlayerCommand>();
+        let (result_send, result_recv) = mpsc::unbounded_channel::<StepResult>();
+        // failed steps running in the background are reported back to stop playing. Every step holds a sender,
+        // so the channel closes once the last one finished
+        let (failed_send, mut failed_recv) = mpsc::unbounded_channel::<StepResult>();
+        let (log_send, log_recv) = mpsc::unbounded_channel::<LoggedCommand>();
+        let (metrics_send, metrics_recv) = watch::channel(device.display_metrics.clone());
 
         let gui_context_async = gui_context.clone();
         tokio::spawn(async move {
-            let mut buffer = String::new();
+            let deadline = stop_conditions.deadline(Instant::now());
+            let mut stop_reason = StopReason::Stopped;
             let mut repetion = 0;
+            let mut stylus_available = None;
+            let mut display_metrics = device.display_metrics.clone();
+            let timeline = resolve_timeline(&inputs);
+            let mut variables = Variables::default();
+            let mut rng = Rng::new(seed);
+            let mut failure = None;
+            let mut breakpoints = Vec::new();
+            let mut paused = false;
+            let mut stepping = false;
+            let mut lateness = Lateness::default();
+            let play_start = Instant::now();
+            // every step is scheduled from the start of the run, so the time spent sending steps doesn't add up.
+            // A dry run doesn't wait, its time only exists in scheduled
+            let mut scheduled = play_start;
+            let now = |scheduled: Instant| if device.dry_run { scheduled } else { Instant::now() };
+            // a dry run doesn't wait, so a goto going back without delay would log forever
+            let mut dry_run_lines = 0;
             'main_loop: loop {
-                let mut last_millis = 0;
+                if let Some((barrier, player)) = &device.barrier {
+                    tokio::select! {
+                        _ = barrier.wait(*player, repetion) => (),
+                        _ = &mut stop_recv => break 'main_loop,
+                    }
+                }
+
+                let mut loops: Vec<LoopPosition> = Vec::new();
+                let mut next_idx = 0;
+                scheduled = now(scheduled);
+
+                while next_idx < inputs.len() {
+                    let idx = next_idx;
+                    next_idx += 1;
+
+                    if device.dry_run {
+                        dry_run_lines += 1;
+                        if dry_run_lines > MAX_DRY_RUN_LINES {
+                            let message = format!("stopped after {} lines, the script may loop forever", MAX_DRY_RUN_LINES);
+                            failure = Some(InputReplayState::Failed { line: idx, message, repetitions: repetion });
+                            break 'main_loop;
+                        }
+                    }
 
-                for (idx, input) in inputs.iter().enumerate() {
                     match stop_recv.try_recv() {
                         Ok(_) | Err(TryRecvError::Closed) => break 'main_loop,
                         Err(TryRecvError::Empty) => (),
                     }
 
-                    let diff = input.timestamp_milliseconds - last_millis;
-                    last_millis = input.timestamp_milliseconds;
+                    if let Some((deadline, reason)) = deadline {
+                        if now(scheduled) >= deadline {
+                            stop_reason = reason;
+                            break 'main_loop;
+                        }
+                    }
+
+                    if let Ok(failed) = failed_recv.try_recv() {
+                        let message = failed.error.unwrap_or_default();
+                        eprintln!("stopping, line {} failed: {}", failed.line, message);
+                        failure = Some(InputReplayState::Failed { line: failed.line, message, repetitions: repetion });
+                        break 'main_loop;
+                    }
+
+                    while let Ok(command) = command_recv.try_recv() {
+                        match command {
+                            PlayerCommand::Pause => paused = true,
+                            PlayerCommand::Resume => stepping = false,
+                            PlayerCommand::Step => stepping = true,
+                            PlayerCommand::SetBreakpoints(b) => breakpoints = b,
+                        }
+                    }
+
+                    // stepping stops before every line that sends something
+                    let is_step = matches!(inputs[idx], ScriptLine::Input(_) | ScriptLine::Template(_));
+                    if breakpoints.contains(&idx) || (stepping && is_step) {
+                        paused = true;
+                    }
+
+                    // waits before the line, its delay and the line itself run after resuming
+                    if paused {
+                        _ = status_send.send(InputReplayState::Paused(Repeating { repetion, reptetion_element: Some(idx), loops: loops.clone(), lateness }));
+                        gui_context_async.request_repaint();
+
+                        loop {
+                            tokio::select! {
+                                command = command_recv.recv() => match command {
+                                    Some(PlayerCommand::Resume) => {
+                                        stepping = false;
+                                        break;
+                                    }
+                                    Some(PlayerCommand::Step) => {
+                                        stepping = true;
+                                        break;
+                                    }
+                                    Some(PlayerCommand::SetBreakpoints(b)) => breakpoints = b,
+                                    Some(PlayerCommand::Pause) => (),
+                                    None => break 'main_loop,
+                                },
+                                _ = &mut stop_recv => break 'main_loop,
+                            }
+                        }
+                        paused = false;
+                        // the time paused is not made up for
+                        scheduled = now(scheduled);
+                    }
+
+                    // every line waits for its own delay after the line before it, so repeated blocks keep their timing
+                    let diff = match idx {
+                        0 => timeline[0],
+                        _ => timeline[idx].saturating_sub(timeline[idx - 1]),
+                    };
 
                     if diff > 0 {
-                        tokio::time::sleep(std::time::Duration::from_millis(diff as u64)).await;
+                        if let Some(reason) = sleep_until_scaled(&mut scheduled, diff, &mut speed_recv, device.dry_run, &mut stop_recv, deadline).await {
+                            stop_reason = reason;
+                            break 'main_loop;
+                        }
+                    }
+
+                    let evaluated;
+                    let input = match &inputs[idx] {
+                        ScriptLine::Input(input) => input,
+                        ScriptLine::RepeatStart(0) => {
+                            next_idx = flow.block_partner(idx).expect("checked by ControlFlow") + 1;
+                            continue;
+                        }
+                        ScriptLine::RepeatStart(count) => {
+                            loops.push(LoopPosition { line: idx, iteration: 0, count: *count });
+                            continue;
+                        }
+                        ScriptLine::RepeatEnd => {
+                            let position = loops.last_mut().expect("checked by ControlFlow");
+                            position.iteration += 1;
+                            if position.iteration < position.count {
+                                next_idx = position.line + 1;
+                            } else {
+                                loops.pop();
+                            }
+                            continue;
+                        }
+                        ScriptLine::Goto(_) => {
+                            next_idx = flow.goto_target(idx).expect("checked by ControlFlow");
+                            // leave the blocks the label is not in
+                            while let Some(position) = loops.last() {
+                                if flow.is_inside(next_idx, Some(position.line)) {
+                                    break;
+                                }
+                                loops.pop();
+                            }
+                            // a jump without delay must not block the runtime
+                            tokio::task::yield_now().await;
+                            continue;
+                        }
+                        ScriptLine::Set { name, expression } => {
+                            match evaluate(expression, &variables, &mut rng) {
+                                Ok(value) => variables.set(name, value),
+                                Err(err) => {
+                                    if let Some(failed) = report_failed_line(&result_send, &stop_conditions, idx, repetion, format!("{:?}", err)) {
+                                        failure = Some(failed);
+                                        break 'main_loop;
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                        ScriptLine::Template(template) => match template.evaluate(&variables, &mut rng) {
+                            Ok(input) => {
+                                // random waits are not part of the timeline, they delay right here
+                                if let Input::Wait(ms) = input.input {
+                                    if let Some(reason) = sleep_until_scaled(&mut scheduled, ms, &mut speed_recv, device.dry_run, &mut stop_recv, deadline).await {
+                                        stop_reason = reason;
+                                        break 'main_loop;
+                                    }
+                                    continue;
+                                }
+                                evaluated = input;
+                                &evaluated
+                            }
+                            Err(err) => {
+                                if let Some(failed) = report_failed_line(&result_send, &stop_conditions, idx, repetion, format!("{:?}", err)) {
+                                    failure = Some(failed);
+                                    break 'main_loop;
+                                }
+                                continue;
+                            }
+                        },
+                        // includes are expanded before playing
+                        ScriptLine::Section(_) | ScriptLine::Comment(_) | ScriptLine::Blank | ScriptLine::Label(_) | ScriptLine::Include(_) => continue,
+                    };
+
+                    // the following steps are already delayed by the timeline
+                    if let Input::Wait(_) = input.input {
+                        continue;
+                    }
+
+                    let mut command = Command::new("adb.exe");
+                    command.stdin(Stdio::null());
+                    if let Some(serial) = &device.serial {
+                        command.arg("-s").arg(serial);
+                    }
+                    command.arg("shell");
+
+                    // coordinates relative to the display are scaled to the display of the device
+                    let mut send_input = input.input.clone();
+                    if let Some(screens) = &device.recorded_screens {
+                        send_input.normalize(screens.display(input.display_id).display_size());
+                    }
+                    if send_input.needs_display_metrics() {
+                        let metrics = match display_metrics.iter().find(|(id, _)| *id == input.display_id) {
+                            Some((_, metrics)) => Some(*metrics),
+                            None if device.offline => {
+                                let message = "display size not known".to_string();
+                                failure = Some(InputReplayState::Failed { line: idx, message, repetitions: repetion });
+                                break 'main_loop;
+                            }
+                            None => {
+                                // a failed query isn't kept, the next step asks again
+                                let metrics = get_display_metrics(device.serial.as_deref(), input.display_id).await;
+                                if let Some(metrics) = metrics {
+                                    display_metrics.push((input.display_id, metrics));
+                                    _ = metrics_send.send(display_metrics.clone());
+                                }
+                                metrics
+                            }
+                        };
+
+                        match metrics {
+                            Some(metrics) => {
+                                // the raw points of a stroke follow the display size like its start and end
+                                if let (Input::Stroke(stroke), Some(screens)) = (&mut send_input, &device.recorded_screens) {
+                                    let screen = screens.display(input.display_id);
+                                    let played = match screen.orientation {
+                                        Orientation::Portrait => (metrics.width, metrics.height),
+                                        Orientation::LandscapeLeft | Orientation::LandscapeRight => (metrics.height, metrics.width),
+                                    };
+                                    stroke.scale_points((screen.width, screen.height), played);
+                                }
+                                send_input.to_pixels(metrics)
+                            }
+                            None => {
+                                let message = "display size not available".to_string();
+                                if let Some(failed) = report_failed_line(&result_send, &stop_conditions, idx, repetion, message) {
+                                    failure = Some(failed);
+                                    break 'main_loop;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+
+                    let speed = *speed_recv.borrow();
+                    if speed.scale_swipes {
+                        send_input.scale_duration(speed.factor);
+                    }
+
+                    // shell steps are waited for, the next step usually depends on them
+                    if let Some(shell_command) = send_input.shell_command(input.display_id) {
+                        if device.dry_run {
+                            command.arg(shell_command);
+                            log_command(&log_send, &command, idx, repetion, scheduled - play_start);
+                            _ = status_send.send(InputReplayState::Repeating(Repeating { repetion, reptetion_element: Some(idx), loops: loops.clone(), lateness }));
+                            gui_context_async.request_repaint();
+                            continue;
+                        }
+
+                        lateness.add(Instant::now().saturating_duration_since(scheduled));
+                        _ = status_send.send(InputReplayState::Repeating(Repeating { repetion, reptetion_element: Some(idx), loops: loops.clone(), lateness }));
+                        gui_context_async.request_repaint();
+
+                        let start = Instant::now();
+                        let output = command
+                            .arg(shell_command)
+                            .creation_flags(NO_WINDOW_FLAGS)
+                            .output()
+                            .await;
+
+                        let result = StepResult::new(idx, repetion, &send_input, output, start.elapsed());
+                        _ = result_send.send(result.clone());
+                        if let (Some(message), false) = (result.error, stop_conditions.continue_on_error) {
+                            eprintln!("stopping, line {} failed: {}", idx, message);
+                            failure = Some(InputReplayState::Failed { line: idx, message, repetitions: repetion });
+                            break 'main_loop;
+                        }
+                        // the following steps are delayed by the time the command took, instead of being sent at once to catch up
+                        scheduled = scheduled.max(Instant::now());
+                        continue;
                     }
 
-                    buffer.clear();
-                    write!(buffer, "{}", input.input).unwrap();
+                    if let Input::Stroke(stroke) = &send_input {
+                        // pressure, tilt and the points between start and end are only replayed by sendevent.
+                        // A dry run doesn't ask the device and logs `input stylus`
+                        let use_input_stylus = !stroke.has_pressure() && match stylus_available {
+                            Some(available) => available,
+                            None if device.dry_run => true,
+                            None => {
+                                let available = is_input_stylus_available(device.serial.as_deref()).await;
+                                stylus_available = Some(available);
+                                available
+                            }
+                        };
 
-                    _ = status_send.send(InputReplayState::Repeating(Repeating { repetion, reptetion_element: Some(idx) }));
+                        if use_input_stylus {
+                            command.arg("input").args(input.input_args(&send_input));
+                        } else {
+                            command.arg(stroke.to_sendevent_script());
+                        }
+                    } else {
+                        command.arg("input").args(input.input_args(&send_input));
+                    }
+
+                    if device.dry_run {
+                        log_command(&log_send, &command, idx, repetion, scheduled - play_start);
+                    } else {
+                        lateness.add(Instant::now().saturating_duration_since(scheduled));
+                    }
+                    _ = status_send.send(InputReplayState::Repeating(Repeating { repetion, reptetion_element: Some(idx), loops: loops.clone(), lateness }));
                     gui_context_async.request_repaint();
 
+                    if device.dry_run {
+                        continue;
+                    }
 
-                    Command::new("adb.exe")
-                        .stdin(Stdio::null())
-                        .arg("shell")
-                        .arg("input")
-                        .args(buffer.split_ascii_whitespace())
-                        .creation_flags(NO_WINDOW_FLAGS)
-                        .spawn()
-                        .expect("adb must be installed");
+                    // waits for adb in the background, a swipe would delay the following steps
+                    let result_send = result_send.clone();
+                    let failed_send = failed_send.clone();
+                    let continue_on_error = stop_conditions.continue_on_error;
+                    tokio::spawn(async move {
+                        let start = Instant::now();
+                        let output = command.creation_flags(NO_WINDOW_FLAGS).output().await;
+
+                        let result = StepResult::new(idx, repetion, &send_input, output, start.elapsed());
+                        if result.error.is_some() && !continue_on_error {
+                            _ = failed_send.send(result.clone());
+                        }
+                        _ = result_send.send(result);
+                    });
                 }
 
                 // input sequence finished
 
-                if let Some(InputWithTimestamp { input : Input::Swipe(s), timestamp_milliseconds: _}) = inputs.last()
-                {
-                    tokio::time::sleep(Duration::from_millis(s.milliseconds as u64)).await;
+                let stopped = match inputs.iter().rev().find_map(ScriptLine::as_input) {
+                    Some(InputWithTimestamp { input : Input::Swipe(s) | Input::Stroke(Stroke { swipe: s, .. }), .. }) => {
+                        if speed_recv.borrow().scale_swipes {
+                            sleep_until_scaled(&mut scheduled, s.milliseconds, &mut speed_recv, device.dry_run, &mut stop_recv, deadline).await
+                        } else if !device.dry_run {
+                            let end = scheduled + Duration::from_millis(s.milliseconds as u64);
+                            tokio::select! {
+                                _ = tokio::time::sleep_until(end.into()) => None,
+                                reason = wait_for_stop(&mut stop_recv, deadline) => Some(reason),
+                            }
+                        } else {
+                            None
+                        }
+                    }
+                    Some(InputWithTimestamp { input : Input::Wait(ms), .. }) => {
+                        sleep_until_scaled(&mut scheduled, *ms, &mut speed_recv, device.dry_run, &mut stop_recv, deadline).await
+                    }
+                    _ => None,
+                };
+                if let Some(reason) = stopped {
+                    stop_reason = reason;
+                    break 'main_loop;
                 }
 
-                _ = status_send.send(InputReplayState::Repeating(Repeating { repetion, reptetion_element: None }));
+                _ = status_send.send(InputReplayState::Repeating(Repeating { repetion, reptetion_element: None, loops: Vec::new(), lateness }));
                 gui_context_async.request_repaint();
 
-                tokio::time::sleep(Duration::from_millis(delay_ms_between_loops as u64)).await;
                 repetion += 1;
+                if stop_conditions.repetitions.map(|r| repetion >= r).unwrap_or(false) {
+                    stop_reason = StopReason::Repetitions;
+                    break 'main_loop;
+                }
+
+                if device.dry_run {
+                    scheduled += Duration::from_millis(delay_ms_between_loops as u64);
+                } else {
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_millis(delay_ms_between_loops as u64)) => (),
+                        reason = wait_for_stop(&mut stop_recv, deadline) => {
+                            stop_reason = reason;
+                            break 'main_loop;
+                        }
+                    }
+                }
+            }
+
+            if let Some((barrier, player)) = &device.barrier {
+                barrier.leave(*player);
             }
 
-            if let Err(_e) = status_send.send(InputReplayState::Finished) {
+            // waits for the steps still running in the background, a failing last step fails the run
+            drop(failed_send);
+            while let Some(failed) = failed_recv.recv().await {
+                if failure.is_none() {
+                    let message = failed.error.unwrap_or_default();
+                    eprintln!("line {} failed: {}", failed.line, message);
+                    failure = Some(InputReplayState::Failed { line: failed.line, message, repetitions: repetion });
+                }
+            }
+
+            let finished = InputReplayState::Finished { repetitions: repetion, reason: stop_reason };
+            if let Err(_e) = status_send.send(failure.unwrap_or(finished)) {
                 eprintln!("error confirming stop: {}", "receiver dropped");
             }
             gui_context_async.request_repaint();
         });
 
-        Self {
+        Ok(Self {
             stop_send: Some(stop_send),
             status_recv,
+            speed_send,
+            command_send,
+            result_recv,
+            log_recv,
+            metrics_recv,
+        })
+    }
+
+    // plays the lines once as dry run and returns the commands that would be sent, for checking scripts in tests.
+    // No device is needed, normalized and dp coordinates need the size of their display in display_metrics
+    pub async fn dry_run(inputs: Arc<Vec<ScriptLine>>, seed : u64, display_metrics : Vec<(Option<i32>, DisplayMetrics)>) -> Result<Vec<LoggedCommand>, DryRunError> {
+        let stop_conditions = StopConditions { repetitions: Some(1), ..Default::default() };
+        let device = PlayDevice { dry_run: true, display_metrics, offline: true, ..Default::default() };
+        let mut player = Self::new(&egui::Context::default(), inputs, 0, stop_conditions, seed, PlaybackSpeed::default(), device)
+            .map_err(DryRunError::ControlFlow)?;
+
+        let mut status_recv = player.status_recv.clone();
+        while player.is_running() {
+            if status_recv.changed().await.is_err() {
+                break;
+            }
+        }
+
+        if let InputReplayState::Failed { line, message, .. } = player.get_current_status() {
+            return Err(DryRunError::Failed { line, message });
+        }
+
+        let mut commands = Vec::new();
+        while let Some(command) = player.read_next_logged_command() {
+            commands.push(command);
         }
+        Ok(commands)
     }
 
     pub fn stop(&mut self) {
@@ -103,12 +748,45 @@ impl InputPlayer {
         }
     }
 
+    pub fn send_command(&self, command: PlayerCommand) {
+        if self.command_send.send(command).is_err() {
+            eprintln!("error sending command: player finished");
+        }
+    }
+
+    // the results of the steps sent since the last call, in the order they finished. Finished once playing ended
+    // and every step reported
+    pub fn read_next_result(&mut self) -> Result<StepResult, ReadNextResultError> {
+        match self.result_recv.try_recv() {
+            Ok(result) => Ok(result),
+            Err(mpsc::error::TryRecvError::Empty) => Err(ReadNextResultError::Empty),
+            Err(mpsc::error::TryRecvError::Disconnected) => Err(ReadNextResultError::Finished),
+        }
+    }
+
+    // the commands of a dry run logged since the last call
+    pub fn read_next_logged_command(&mut self) -> Option<LoggedCommand> {
+        self.log_recv.try_recv().ok()
+    }
+
+    // the display sizes given to the player and the ones it asked the device for
+    pub fn display_metrics(&self) -> Vec<(Option<i32>, DisplayMetrics)> {
+        self.metrics_recv.borrow().clone()
+    }
+
+    // takes effect immediately, also for the delay currently waited
+    pub fn set_speed(&self, speed: PlaybackSpeed) {
+        _ = self.speed_send.send(speed);
+    }
+
     pub fn get_current_status(&self) -> InputReplayState {
         self.status_recv.borrow().clone()
     }
 
+    // false after stopping and when playing ended on its own
     pub fn is_running(&self) -> bool {
         self.stop_send.is_some()
+            && !matches!(*self.status_recv.borrow(), InputReplayState::Finished { .. } | InputReplayState::Failed { .. })
     }
 }
 
@@ -117,3 +795,234 @@ impl Drop for InputPlayer {
         self.stop();
     }
 }
+
+// returns when stop is sent or the player is dropped, or at the deadline
+async fn wait_for_stop(stop: &mut oneshot::Receiver<()>, deadline: Option<(Instant, StopReason)>) -> StopReason {
+    match deadline {
+        Some((deadline, reason)) => tokio::select! {
+            _ = stop => StopReason::Stopped,
+            _ = tokio::time::sleep_until(deadline.into()) => reason,
+        },
+        None => {
+            _ = stop.await;
+            StopReason::Stopped
+        }
+    }
+}
+
+// moves scheduled the given milliseconds of script time further and sleeps until then. A speed change while
+// sleeping applies to the rest of the time. When scheduled has already passed it returns at once.
+// Returns why playing has to stop when stop is sent or the deadline passes before
+async fn sleep_until_scaled(
+    scheduled: &mut Instant,
+    milliseconds: u32,
+    speed: &mut watch::Receiver<PlaybackSpeed>,
+    dry_run: bool,
+    stop: &mut oneshot::Receiver<()>,
+    deadline: Option<(Instant, StopReason)>,
+) -> Option<StopReason> {
+    let mut factor = speed.borrow().factor.max(0.01) as f64;
+    *scheduled += Duration::from_secs_f64(milliseconds as f64 / factor / 1000.0);
+    if dry_run {
+        // the time of a dry run only exists in scheduled
+        return deadline.filter(|(deadline, _)| *scheduled >= *deadline).map(|(_, reason)| reason);
+    }
+
+    loop {
+        let spin_wait = speed.borrow().spin_wait;
+        let wake_up = match spin_wait {
+            true => scheduled.checked_sub(SPIN_WAIT).unwrap_or(*scheduled),
+            false => *scheduled,
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep_until(wake_up.into()) => break,
+            changed = speed.changed() => {
+                if changed.is_err() {
+                    // the player is gone, the speed won't change anymore
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(wake_up.into()) => break,
+                        reason = wait_for_stop(stop, deadline) => return Some(reason),
+                    }
+                }
+                let new_factor = speed.borrow().factor.max(0.01) as f64;
+                let now = Instant::now();
+                let remaining = scheduled.saturating_duration_since(now).as_secs_f64() * factor / new_factor;
+                *scheduled = now + Duration::from_secs_f64(remaining);
+                factor = new_factor;
+            }
+            reason = wait_for_stop(stop, deadline) => return Some(reason),
+        }
+    }
+
+    if speed.borrow().spin_wait {
+        // yields so other tasks still run, but the thread stays busy
+        while Instant::now() < *scheduled {
+            tokio::task::yield_now().await;
+        }
+    }
+    None
+}
+
+// reports a line that failed before anything was sent like a failed step, the state to stop with if failing steps stop playing
+fn report_failed_line(result_send: &mpsc::UnboundedSender<StepResult>, stop_conditions: &StopConditions, line: usize, repetition: u32, message: String) -> Option<InputReplayState> {
+    _ = result_send.send(StepResult::failed(line, repetition, message.clone()));
+    if stop_conditions.continue_on_error {
+        eprintln!("skipping line {}, {}", line, message);
+        return None;
+    }
+    eprintln!("stopping, line {} failed: {}", line, message);
+    Some(InputReplayState::Failed { line, message, repetitions: repetition })
+}
+
+fn log_command(log_send: &mpsc::UnboundedSender<LoggedCommand>, command: &Command, line: usize, repetition: u32, time: Duration) {
+    let args = command.as_std().get_args().map(|a| a.to_string_lossy().to_string()).collect();
+    _ = log_send.send(LoggedCommand { line, repetition, time, args });
+}
+
+fn check_shell_output(input: &Input, output: std::io::Result<Output>) -> Result<(), String> {
+    let output = output.map_err(|err| format!("adb could not be run: {}", err))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    if !output.status.success() {
+        let code = output.status.code().map(|c| c.to_string()).unwrap_or_else(|| "none".to_string());
+        return Err(format!("exit code {}: {}{}", code, stderr.trim(), stdout.trim()));
+    }
+
+    // am, pm and input exit with 0 on older android versions even if they failed
+    match input {
+        Input::Launch(_) | Input::OpenUri(_) if stdout.contains("Error") || stderr.contains("Error") => {
+            Err(format!("{}{}", stderr.trim(), stdout.trim()))
+        }
+        Input::ClearData(_) if stdout.trim() != "Success" => Err(format!("{}{}", stderr.trim(), stdout.trim())),
+        // the output of shell steps is up to the user
+        Input::Shell(_) => Ok(()),
+        _ if stderr.contains("Exception") || stdout.contains("Exception") => Err(format!("{}{}", stderr.trim(), stdout.trim())),
+        _ => Ok(()),
+    }
+}
+
+// the usage text of `input` lists the sources it can send from, `stylus` is missing on old android versions
+async fn is_input_stylus_available(serial: Option<&str>) -> bool {
+    let mut command = Command::new("adb.exe");
+    if let Some(serial) = serial {
+        command.arg("-s").arg(serial);
+    }
+    let output = command
+        .stdin(Stdio::null())
+        .arg("shell")
+        .arg("input")
+        .creation_flags(NO_WINDOW_FLAGS)
+        .output()
+        .await;
+
+    match output {
+        Ok(output) => {
+            String::from_utf8_lossy(&output.stdout).contains("stylus")
+                || String::from_utf8_lossy(&output.stderr).contains("stylus")
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(text: &str, stop_conditions: StopConditions) -> InputPlayer {
+        let lines = text.lines().map(|line| line.parse().unwrap()).collect();
+        InputPlayer::new(&egui::Context::default(), Arc::new(lines), 0, stop_conditions, 0, PlaybackSpeed::default(), PlayDevice::default())
+            .ok()
+            .unwrap()
+    }
+
+    async fn wait_until_done(player: &InputPlayer) -> InputReplayState {
+        let mut status_recv = player.status_recv.clone();
+        let done = async {
+            while !matches!(*status_recv.borrow(), InputReplayState::Finished { .. } | InputReplayState::Failed { .. }) {
+                status_recv.changed().await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), done).await.expect("player didn't end");
+        player.get_current_status()
+    }
+
+    async fn dry_run(text: &str, display_metrics: Vec<(Option<i32>, DisplayMetrics)>) -> Result<Vec<Vec<String>>, DryRunError> {
+        let lines = text.lines().map(|line| line.parse().unwrap()).collect();
+        let commands = InputPlayer::dry_run(Arc::new(lines), 0, display_metrics).await?;
+        Ok(commands.into_iter().map(|c| c.args).collect())
+    }
+
+    #[tokio::test]
+    async fn dry_run_lists_commands_with_their_time() {
+        let lines: Vec<ScriptLine> = ["0 tap 1 2", "repeat 2 {", "+100ms keyevent KEYCODE_BACK", "}", "shell echo hi"]
+            .iter()
+            .map(|line| line.parse().unwrap())
+            .collect();
+        let commands = InputPlayer::dry_run(Arc::new(lines), 0, Vec::new()).await.unwrap();
+        let times: Vec<u128> = commands.iter().map(|c| c.time.as_millis()).collect();
+        assert_eq!(times, vec![0, 100, 200, 200]);
+        assert_eq!(commands[0].args, vec!["shell", "input", "tap", "1", "2"]);
+        assert_eq!(commands[3].args, vec!["shell", "echo hi"]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_scales_to_the_given_display() {
+        let metrics = DisplayMetrics { width: 1000, height: 2000, density: 320 };
+        let commands = dry_run("0 tap 50% 0.2500\n+10ms tap 10dp 10dp", vec![(None, metrics)]).await.unwrap();
+        assert_eq!(commands[0][2..], ["tap", "500", "500"]);
+        assert_eq!(commands[1][2..], ["tap", "20", "20"]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_fails_without_display_size() {
+        assert!(matches!(dry_run("0 tap 0.5000 0.5000", Vec::new()).await, Err(DryRunError::Failed { line: 0, .. })));
+    }
+
+    #[tokio::test]
+    async fn dry_run_of_an_endless_loop_ends() {
+        let res = dry_run("label a\n0 tap 1 1\ngoto a", Vec::new()).await;
+        assert!(matches!(res, Err(DryRunError::Failed { .. })));
+    }
+
+    #[tokio::test]
+    async fn dry_run_logs_strokes_as_input_stylus() {
+        let commands = dry_run("0 stroke 1 1 5 5 100 ev2", Vec::new()).await.unwrap();
+        assert_eq!(commands[0][1..4], ["input", "stylus", "swipe"]);
+    }
+
+    #[tokio::test]
+    async fn deadline_ends_a_long_wait() {
+        let stop_conditions = StopConditions { max_duration: Some(Duration::from_millis(50)), ..Default::default() };
+        let player = player("wait 3600000\ntap 1 1", stop_conditions);
+        let status = wait_until_done(&player).await;
+        assert!(matches!(status, InputReplayState::Finished { reason: StopReason::MaxDuration, .. }));
+    }
+
+    #[tokio::test]
+    async fn failing_expression_stops_playing() {
+        let mut player = player("set x = 1/0\nwait 3600000", StopConditions::default());
+        let status = wait_until_done(&player).await;
+        assert!(matches!(status, InputReplayState::Failed { line: 0, .. }));
+        assert!(player.read_next_result().ok().unwrap().error.is_some());
+    }
+
+    #[tokio::test]
+    async fn failing_expression_is_listed_when_continuing() {
+        let stop_conditions = StopConditions { continue_on_error: true, repetitions: Some(1), ..Default::default() };
+        let mut player = player("set x = $missing\nwait 10", stop_conditions);
+        let status = wait_until_done(&player).await;
+        assert!(matches!(status, InputReplayState::Finished { reason: StopReason::Repetitions, .. }));
+        assert!(player.read_next_result().ok().unwrap().error.is_some());
+    }
+
+    #[tokio::test]
+    async fn stop_ends_a_long_wait() {
+        let mut player = player("wait 3600000\ntap 1 1", StopConditions::default());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        player.stop();
+        let status = wait_until_done(&player).await;
+        assert!(matches!(status, InputReplayState::Finished { reason: StopReason::Stopped, .. }));
+    }
+}
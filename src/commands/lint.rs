@@ -0,0 +1,253 @@
+use core::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::{
+    input::{line_timings, Coordinate, Input, Key, ScriptLine, Stroke},
+    input_event_recorder::ScreenInfos,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    // plays, but likely not as intended
+    Warning,
+    // the step can't do what it says
+    Error,
+}
+
+#[derive(Clone)]
+pub enum DiagnosticKind {
+    // an absolute time before the step before it, the step runs right after that one instead
+    TimeGoesBack { time: u32, previous: u32 },
+    // a point outside the display, size is the display in pixels if it is known and the point is in pixels
+    OutOfBounds { x: Coordinate, y: Coordinate, size: Option<(i32, i32)> },
+    // a swipe ending where it started
+    ZeroLengthSwipe,
+    // a gesture starting before the one on the given line ended
+    OverlappingGesture { other_line: usize },
+    // a key code not in the list of known ones, adb may not know it either
+    UnknownKey(String),
+}
+
+// something the linter found on a line of the script
+#[derive(Clone)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            DiagnosticKind::TimeGoesBack { time, previous } => {
+                write!(f, "time {}ms is before the previous step at {}ms, runs right after it", time, previous)
+            }
+            DiagnosticKind::OutOfBounds { x, y, size: Some((width, height)) } => {
+                write!(f, "point {} {} is outside the {}x{} display", x, y, width, height)
+            }
+            DiagnosticKind::OutOfBounds { x, y, size: None } => {
+                write!(f, "point {} {} is outside the display", x, y)
+            }
+            DiagnosticKind::ZeroLengthSwipe => write!(f, "swipe ends where it starts"),
+            DiagnosticKind::OverlappingGesture { other_line } => {
+                write!(f, "starts before the gesture on line {} ended", other_line)
+            }
+            DiagnosticKind::UnknownKey(code) => write!(f, "{} is not a known key code", code),
+        }
+    }
+}
+
+// the key codes of android.view.KeyEvent, the index is the number of the key code
+const KEYCODES: &[&str] = &[
+    "KEYCODE_UNKNOWN", "KEYCODE_SOFT_LEFT", "KEYCODE_SOFT_RIGHT", "KEYCODE_HOME", "KEYCODE_BACK", "KEYCODE_CALL",
+    "KEYCODE_ENDCALL", "KEYCODE_0", "KEYCODE_1", "KEYCODE_2", "KEYCODE_3", "KEYCODE_4", "KEYCODE_5", "KEYCODE_6",
+    "KEYCODE_7", "KEYCODE_8", "KEYCODE_9", "KEYCODE_STAR", "KEYCODE_POUND", "KEYCODE_DPAD_UP", "KEYCODE_DPAD_DOWN",
+    "KEYCODE_DPAD_LEFT", "KEYCODE_DPAD_RIGHT", "KEYCODE_DPAD_CENTER", "KEYCODE_VOLUME_UP", "KEYCODE_VOLUME_DOWN",
+    "KEYCODE_POWER", "KEYCODE_CAMERA", "KEYCODE_CLEAR", "KEYCODE_A", "KEYCODE_B", "KEYCODE_C", "KEYCODE_D",
+    "KEYCODE_E", "KEYCODE_F", "KEYCODE_G", "KEYCODE_H", "KEYCODE_I", "KEYCODE_J", "KEYCODE_K", "KEYCODE_L",
+    "KEYCODE_M", "KEYCODE_N", "KEYCODE_O", "KEYCODE_P", "KEYCODE_Q", "KEYCODE_R", "KEYCODE_S", "KEYCODE_T",
+    "KEYCODE_U", "KEYCODE_V", "KEYCODE_W", "KEYCODE_X", "KEYCODE_Y", "KEYCODE_Z", "KEYCODE_COMMA", "KEYCODE_PERIOD",
+    "KEYCODE_ALT_LEFT", "KEYCODE_ALT_RIGHT", "KEYCODE_SHIFT_LEFT", "KEYCODE_SHIFT_RIGHT", "KEYCODE_TAB",
+    "KEYCODE_SPACE", "KEYCODE_SYM", "KEYCODE_EXPLORER", "KEYCODE_ENVELOPE", "KEYCODE_ENTER", "KEYCODE_DEL",
+    "KEYCODE_GRAVE", "KEYCODE_MINUS", "KEYCODE_EQUALS", "KEYCODE_LEFT_BRACKET", "KEYCODE_RIGHT_BRACKET",
+    "KEYCODE_BACKSLASH", "KEYCODE_SEMICOLON", "KEYCODE_APOSTROPHE", "KEYCODE_SLASH", "KEYCODE_AT", "KEYCODE_NUM",
+    "KEYCODE_HEADSETHOOK", "KEYCODE_FOCUS", "KEYCODE_PLUS", "KEYCODE_MENU", "KEYCODE_NOTIFICATION",
+    "KEYCODE_SEARCH", "KEYCODE_MEDIA_PLAY_PAUSE", "KEYCODE_MEDIA_STOP", "KEYCODE_MEDIA_NEXT",
+    "KEYCODE_MEDIA_PREVIOUS", "KEYCODE_MEDIA_REWIND", "KEYCODE_MEDIA_FAST_FORWARD", "KEYCODE_MUTE",
+    "KEYCODE_PAGE_UP", "KEYCODE_PAGE_DOWN", "KEYCODE_PICTSYMBOLS", "KEYCODE_SWITCH_CHARSET", "KEYCODE_BUTTON_A",
+    "KEYCODE_BUTTON_B", "KEYCODE_BUTTON_C", "KEYCODE_BUTTON_X", "KEYCODE_BUTTON_Y", "KEYCODE_BUTTON_Z",
+    "KEYCODE_BUTTON_L1", "KEYCODE_BUTTON_R1", "KEYCODE_BUTTON_L2", "KEYCODE_BUTTON_R2", "KEYCODE_BUTTON_THUMBL",
+    "KEYCODE_BUTTON_THUMBR", "KEYCODE_BUTTON_START", "KEYCODE_BUTTON_SELECT", "KEYCODE_BUTTON_MODE",
+    "KEYCODE_ESCAPE", "KEYCODE_FORWARD_DEL", "KEYCODE_CTRL_LEFT", "KEYCODE_CTRL_RIGHT", "KEYCODE_CAPS_LOCK",
+    "KEYCODE_SCROLL_LOCK", "KEYCODE_META_LEFT", "KEYCODE_META_RIGHT", "KEYCODE_FUNCTION", "KEYCODE_SYSRQ",
+    "KEYCODE_BREAK", "KEYCODE_MOVE_HOME", "KEYCODE_MOVE_END", "KEYCODE_INSERT", "KEYCODE_FORWARD",
+    "KEYCODE_MEDIA_PLAY", "KEYCODE_MEDIA_PAUSE", "KEYCODE_MEDIA_CLOSE", "KEYCODE_MEDIA_EJECT",
+    "KEYCODE_MEDIA_RECORD", "KEYCODE_F1", "KEYCODE_F2", "KEYCODE_F3", "KEYCODE_F4", "KEYCODE_F5", "KEYCODE_F6",
+    "KEYCODE_F7", "KEYCODE_F8", "KEYCODE_F9", "KEYCODE_F10", "KEYCODE_F11", "KEYCODE_F12", "KEYCODE_NUM_LOCK",
+    "KEYCODE_NUMPAD_0", "KEYCODE_NUMPAD_1", "KEYCODE_NUMPAD_2", "KEYCODE_NUMPAD_3", "KEYCODE_NUMPAD_4",
+    "KEYCODE_NUMPAD_5", "KEYCODE_NUMPAD_6", "KEYCODE_NUMPAD_7", "KEYCODE_NUMPAD_8", "KEYCODE_NUMPAD_9",
+    "KEYCODE_NUMPAD_DIVIDE", "KEYCODE_NUMPAD_MULTIPLY", "KEYCODE_NUMPAD_SUBTRACT", "KEYCODE_NUMPAD_ADD",
+    "KEYCODE_NUMPAD_DOT", "KEYCODE_NUMPAD_COMMA", "KEYCODE_NUMPAD_ENTER", "KEYCODE_NUMPAD_EQUALS",
+    "KEYCODE_NUMPAD_LEFT_PAREN", "KEYCODE_NUMPAD_RIGHT_PAREN", "KEYCODE_VOLUME_MUTE", "KEYCODE_INFO",
+    "KEYCODE_CHANNEL_UP", "KEYCODE_CHANNEL_DOWN", "KEYCODE_ZOOM_IN", "KEYCODE_ZOOM_OUT", "KEYCODE_TV",
+    "KEYCODE_WINDOW", "KEYCODE_GUIDE", "KEYCODE_DVR", "KEYCODE_BOOKMARK", "KEYCODE_CAPTIONS", "KEYCODE_SETTINGS",
+    "KEYCODE_TV_POWER", "KEYCODE_TV_INPUT", "KEYCODE_STB_POWER", "KEYCODE_STB_INPUT", "KEYCODE_AVR_POWER",
+    "KEYCODE_AVR_INPUT", "KEYCODE_PROG_RED", "KEYCODE_PROG_GREEN", "KEYCODE_PROG_YELLOW", "KEYCODE_PROG_BLUE",
+    "KEYCODE_APP_SWITCH", "KEYCODE_BUTTON_1", "KEYCODE_BUTTON_2", "KEYCODE_BUTTON_3", "KEYCODE_BUTTON_4",
+    "KEYCODE_BUTTON_5", "KEYCODE_BUTTON_6", "KEYCODE_BUTTON_7", "KEYCODE_BUTTON_8", "KEYCODE_BUTTON_9",
+    "KEYCODE_BUTTON_10", "KEYCODE_BUTTON_11", "KEYCODE_BUTTON_12", "KEYCODE_BUTTON_13", "KEYCODE_BUTTON_14",
+    "KEYCODE_BUTTON_15", "KEYCODE_BUTTON_16", "KEYCODE_LANGUAGE_SWITCH", "KEYCODE_MANNER_MODE", "KEYCODE_3D_MODE",
+    "KEYCODE_CONTACTS", "KEYCODE_CALENDAR", "KEYCODE_MUSIC", "KEYCODE_CALCULATOR", "KEYCODE_ZENKAKU_HANKAKU",
+    "KEYCODE_EISU", "KEYCODE_MUHENKAN", "KEYCODE_HENKAN", "KEYCODE_KATAKANA_HIRAGANA", "KEYCODE_YEN", "KEYCODE_RO",
+    "KEYCODE_KANA", "KEYCODE_ASSIST", "KEYCODE_BRIGHTNESS_DOWN", "KEYCODE_BRIGHTNESS_UP",
+    "KEYCODE_MEDIA_AUDIO_TRACK", "KEYCODE_SLEEP", "KEYCODE_WAKEUP", "KEYCODE_PAIRING", "KEYCODE_MEDIA_TOP_MENU",
+    "KEYCODE_11", "KEYCODE_12", "KEYCODE_LAST_CHANNEL", "KEYCODE_TV_DATA_SERVICE", "KEYCODE_VOICE_ASSIST",
+    "KEYCODE_TV_RADIO_SERVICE", "KEYCODE_TV_TELETEXT", "KEYCODE_TV_NUMBER_ENTRY", "KEYCODE_TV_TERRESTRIAL_ANALOG",
+    "KEYCODE_TV_TERRESTRIAL_DIGITAL", "KEYCODE_TV_SATELLITE", "KEYCODE_TV_SATELLITE_BS", "KEYCODE_TV_SATELLITE_CS",
+    "KEYCODE_TV_SATELLITE_SERVICE", "KEYCODE_TV_NETWORK", "KEYCODE_TV_ANTENNA_CABLE", "KEYCODE_TV_INPUT_HDMI_1",
+    "KEYCODE_TV_INPUT_HDMI_2", "KEYCODE_TV_INPUT_HDMI_3", "KEYCODE_TV_INPUT_HDMI_4", "KEYCODE_TV_INPUT_COMPOSITE_1",
+    "KEYCODE_TV_INPUT_COMPOSITE_2", "KEYCODE_TV_INPUT_COMPONENT_1", "KEYCODE_TV_INPUT_COMPONENT_2",
+    "KEYCODE_TV_INPUT_VGA_1", "KEYCODE_TV_AUDIO_DESCRIPTION", "KEYCODE_TV_AUDIO_DESCRIPTION_MIX_UP",
+    "KEYCODE_TV_AUDIO_DESCRIPTION_MIX_DOWN", "KEYCODE_TV_ZOOM_MODE", "KEYCODE_TV_CONTENTS_MENU",
+    "KEYCODE_TV_MEDIA_CONTEXT_MENU", "KEYCODE_TV_TIMER_PROGRAMMING", "KEYCODE_HELP", "KEYCODE_NAVIGATE_PREVIOUS",
+    "KEYCODE_NAVIGATE_NEXT", "KEYCODE_NAVIGATE_IN", "KEYCODE_NAVIGATE_OUT", "KEYCODE_STEM_PRIMARY",
+    "KEYCODE_STEM_1", "KEYCODE_STEM_2", "KEYCODE_STEM_3", "KEYCODE_DPAD_UP_LEFT", "KEYCODE_DPAD_DOWN_LEFT",
+    "KEYCODE_DPAD_UP_RIGHT", "KEYCODE_DPAD_DOWN_RIGHT", "KEYCODE_MEDIA_SKIP_FORWARD", "KEYCODE_MEDIA_SKIP_BACKWARD",
+    "KEYCODE_MEDIA_STEP_FORWARD", "KEYCODE_MEDIA_STEP_BACKWARD", "KEYCODE_SOFT_SLEEP", "KEYCODE_CUT",
+    "KEYCODE_COPY", "KEYCODE_PASTE", "KEYCODE_SYSTEM_NAVIGATION_UP", "KEYCODE_SYSTEM_NAVIGATION_DOWN",
+    "KEYCODE_SYSTEM_NAVIGATION_LEFT", "KEYCODE_SYSTEM_NAVIGATION_RIGHT", "KEYCODE_ALL_APPS", "KEYCODE_REFRESH",
+    "KEYCODE_THUMBS_UP", "KEYCODE_THUMBS_DOWN", "KEYCODE_PROFILE_SWITCH", "KEYCODE_VIDEO_APP_1",
+    "KEYCODE_VIDEO_APP_2", "KEYCODE_VIDEO_APP_3", "KEYCODE_VIDEO_APP_4", "KEYCODE_VIDEO_APP_5",
+    "KEYCODE_VIDEO_APP_6", "KEYCODE_VIDEO_APP_7", "KEYCODE_VIDEO_APP_8", "KEYCODE_FEATURED_APP_1",
+    "KEYCODE_FEATURED_APP_2", "KEYCODE_FEATURED_APP_3", "KEYCODE_FEATURED_APP_4", "KEYCODE_DEMO_APP_1",
+    "KEYCODE_DEMO_APP_2", "KEYCODE_DEMO_APP_3", "KEYCODE_DEMO_APP_4", "KEYCODE_KEYBOARD_BACKLIGHT_DOWN",
+    "KEYCODE_KEYBOARD_BACKLIGHT_UP", "KEYCODE_KEYBOARD_BACKLIGHT_TOGGLE", "KEYCODE_STYLUS_BUTTON_PRIMARY",
+    "KEYCODE_STYLUS_BUTTON_SECONDARY", "KEYCODE_STYLUS_BUTTON_TERTIARY", "KEYCODE_STYLUS_BUTTON_TAIL",
+    "KEYCODE_RECENT_APPS", "KEYCODE_MACRO_1", "KEYCODE_MACRO_2", "KEYCODE_MACRO_3", "KEYCODE_MACRO_4",
+];
+
+// checks the lines of a script before playing it. Points given in pixels can only be checked against the recorded
+// screens, lines with expressions are only known when playing and not checked
+pub fn lint_script(lines: &[ScriptLine], screen_infos: Option<&ScreenInfos>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut push = |line, severity, kind| diagnostics.push(Diagnostic { line, severity, kind });
+
+    // (line, display, end time) of the last gesture
+    let mut last_gesture: Option<(usize, Option<i32>, u32)> = None;
+
+    for (idx, (line, timing)) in lines.iter().zip(line_timings(lines)).enumerate() {
+        // the timeline runs an absolute time going back right after the previous step, that is reported here
+        if let Some((ms, false)) = line.timing() {
+            if ms.saturating_add(timing.shift) < timing.previous {
+                push(idx, Severity::Warning, DiagnosticKind::TimeGoesBack { time: ms, previous: timing.previous - timing.shift });
+            }
+        }
+        let time = timing.time;
+
+        let input = match line.as_input() {
+            Some(input) => input,
+            None => continue,
+        };
+
+        let size = screen_infos.map(|s| s.display(input.display_id).display_size());
+        for (x, y) in points(&input.input) {
+            if !is_on_screen(x, y, size) {
+                let size = if x.is_pixels() && y.is_pixels() { size } else { None };
+                push(idx, Severity::Error, DiagnosticKind::OutOfBounds { x, y, size });
+            }
+        }
+
+        let gesture_ms = match &input.input {
+            Input::Swipe(s) | Input::Stroke(Stroke { swipe: s, .. }) => {
+                if s.x[0] == s.x[1] && s.y[0] == s.y[1] {
+                    push(idx, Severity::Warning, DiagnosticKind::ZeroLengthSwipe);
+                }
+                Some(s.milliseconds)
+            }
+            Input::Tap(_) | Input::Scroll(_) => Some(0),
+            Input::Key(Key::Code(code)) => {
+                if !is_known_keycode(code) {
+                    push(idx, Severity::Warning, DiagnosticKind::UnknownKey(code.clone()));
+                }
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(ms) = gesture_ms {
+            if let Some((other_line, display_id, end)) = last_gesture {
+                if display_id == input.display_id && time < end {
+                    push(idx, Severity::Warning, DiagnosticKind::OverlappingGesture { other_line });
+                }
+            }
+            last_gesture = Some((idx, input.display_id, time.saturating_add(ms)));
+        }
+    }
+
+    diagnostics
+}
+
+// the points an input touches
+fn points(input: &Input) -> Vec<(Coordinate, Coordinate)> {
+    match input {
+        Input::Tap(t) => vec![(t.x, t.y)],
+        Input::Swipe(s) | Input::Stroke(Stroke { swipe: s, .. }) => vec![(s.x[0], s.y[0]), (s.x[1], s.y[1])],
+        Input::Scroll(s) => vec![(s.x, s.y)],
+        _ => Vec::new(),
+    }
+}
+
+// size is the display the input is sent to, if it is known. Dp depend on the density of the device and are not checked
+fn is_on_screen(x: Coordinate, y: Coordinate, size: Option<(i32, i32)>) -> bool {
+    let is_inside = |c: Coordinate, extent: Option<i32>| match (c, extent) {
+        (Coordinate::Normalized(n), _) => (0.0..=1.0).contains(&n),
+        (Coordinate::Pixels(p), Some(extent)) if extent > 0 => (0..extent).contains(&p),
+        (Coordinate::Pixels(p), _) => p >= 0,
+        (Coordinate::Dp(dp), _) => dp >= 0.0,
+    };
+    is_inside(x, size.map(|s| s.0)) && is_inside(y, size.map(|s| s.1))
+}
+
+fn is_known_keycode(code: &str) -> bool {
+    match code.parse::<u32>() {
+        Ok(number) => (number as usize) < KEYCODES.len(),
+        Err(_) => KEYCODES.contains(&code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(text: &str) -> Vec<Diagnostic> {
+        let lines: Vec<ScriptLine> = text.lines().map(|line| line.parse().unwrap()).collect();
+        lint_script(&lines, None)
+    }
+
+    #[test]
+    fn all_key_codes_are_known() {
+        for code in ["KEYCODE_BUTTON_A", "KEYCODE_F12", "KEYCODE_STAR", "KEYCODE_POUND", "KEYCODE_NUMPAD_5", "KEYCODE_MACRO_4", "316"] {
+            assert!(is_known_keycode(code), "{}", code);
+        }
+        for code in ["KEYCODE_NOPE", "317"] {
+            assert!(!is_known_keycode(code), "{}", code);
+        }
+        assert_eq!(KEYCODES[96], "KEYCODE_BUTTON_A");
+        assert_eq!(KEYCODES[131], "KEYCODE_F1");
+    }
+
+    #[test]
+    fn backward_time_is_reported_once() {
+        let diagnostics = lint("1000 tap 1 1\n500 tap 2 2\n+10ms tap 3 3");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 1);
+        assert!(matches!(diagnostics[0].kind, DiagnosticKind::TimeGoesBack { time: 500, previous: 1000 }));
+    }
+
+    #[test]
+    fn overlapping_gestures_and_bounds() {
+        let diagnostics = lint("0 swipe 1 1 100 100 500\n100 tap 1.5000 0.5000");
+        let kinds: Vec<_> = diagnostics.iter().map(|d| (d.line, d.severity)).collect();
+        assert_eq!(kinds, vec![(1, Severity::Error), (1, Severity::Warning)]);
+    }
+}
@@ -1,8 +1,14 @@
+pub mod control_flow;
 pub mod device_entry;
+pub mod event_log;
+pub mod export;
+pub mod expression;
 pub mod input;
 pub mod input_event;
 pub mod input_event_parser;
 pub mod input_event_recorder;
 pub mod input_player;
+pub mod lint;
+pub mod script;
 
 const NO_WINDOW_FLAGS : u32 = 0x08000000;
@@ -0,0 +1,281 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    control_flow::{ControlFlow, ControlFlowError},
+    input::{deser_inputs_fmt, to_relative_timing, InputWithTimestamp, ScriptLine},
+    input_event_recorder::{ScreenInfo, ScreenInfos},
+};
+
+// increase when the document changes in a way older versions can't read
+// 1: inputs only
+// 2: lines with comments and sections
+// 3: repeat blocks, labels and goto
+// 4: variables and expressions
+// 5: includes
+// 6: shell and app steps
+pub const SCRIPT_FORMAT_VERSION: u32 = 6;
+
+#[derive(Clone, Debug)]
+pub enum ScriptError {
+    Io,
+    // a line of the legacy line format can't be read
+    Parse,
+    // the versioned document is broken, with the message of the parser
+    Document(String),
+    Serialize,
+    UnsupportedVersion(u32),
+    // loading an included script failed
+    Include(PathBuf, Box<ScriptError>),
+    // the script includes itself, directly or through other scripts
+    IncludeCycle(PathBuf),
+    ControlFlow(PathBuf, ControlFlowError),
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct Script {
+    pub header: ScriptHeader,
+    pub lines: Vec<ScriptLine>,
+}
+
+#[derive(serde::Deserialize)]
+struct ScriptV1 {
+    header: ScriptHeader,
+    inputs: Vec<InputWithTimestamp>,
+}
+
+// only the version of a document, read first so newer documents are reported before their body fails to parse
+#[derive(serde::Deserialize)]
+struct VersionProbe {
+    header: HeaderVersion,
+}
+
+#[derive(serde::Deserialize)]
+struct HeaderVersion {
+    // 0 when missing, like in documents of the first version
+    #[serde(default)]
+    format_version: u32,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)] // scripts imported from the legacy format have no metadata
+pub struct ScriptHeader {
+    pub format_version: u32,
+    pub device: Option<DeviceInfo>,
+    pub screen: Option<ScreenInfo>,
+    pub created_unix_seconds: Option<u64>,
+    pub conversion: Option<ConversionSettings>,
+    // all displays and input devices, needed to convert the event log again
+    pub screen_infos: Option<ScreenInfos>,
+}
+
+impl Default for ScriptHeader {
+    fn default() -> Self {
+        Self {
+            format_version: SCRIPT_FORMAT_VERSION,
+            device: None,
+            screen: None,
+            created_unix_seconds: None,
+            conversion: None,
+            screen_infos: None,
+        }
+    }
+}
+
+impl ScriptHeader {
+    pub fn new(
+        device: Option<DeviceInfo>,
+        screen: Option<ScreenInfo>,
+        conversion: ConversionSettings,
+    ) -> Self {
+        let created_unix_seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+
+        Self {
+            format_version: SCRIPT_FORMAT_VERSION,
+            device,
+            screen,
+            created_unix_seconds,
+            conversion: Some(conversion),
+            screen_infos: None,
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct DeviceInfo {
+    pub model: String,
+    pub serial: String,
+}
+
+// the thresholds the raw events were converted with
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct ConversionSettings {
+    pub tap_threshold_distance: u32,
+    pub tap_threshold_ms: u32,
+    #[serde(default)]
+    pub normalize_coordinates: bool,
+}
+
+pub fn serialize_script<T: Write>(script: &Script, writer: &mut T) -> Result<(), ScriptError> {
+    let text = ron::ser::to_string_pretty(script, ron::ser::PrettyConfig::default())
+        .map_err(|_| ScriptError::Serialize)?;
+    writer.write_all(text.as_bytes()).map_err(|_| ScriptError::Io)
+}
+
+// reads the versioned document, text that doesn't start like one is imported as legacy line format
+pub fn deser_script<T: BufRead>(reader: &mut T) -> Result<Script, ScriptError> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text).map_err(|_| ScriptError::Io)?;
+
+    // documents are written as "(header: ...", no line of the line format starts with '('
+    if !text.trim_start().starts_with('(') {
+        let lines = deser_inputs_fmt(&mut text.as_bytes()).map_err(|_| ScriptError::Parse)?;
+        return Ok(Script {
+            header: ScriptHeader::default(),
+            lines,
+        });
+    }
+
+    if let Ok(VersionProbe { header: HeaderVersion { format_version } }) = ron::from_str(&text) {
+        if format_version > SCRIPT_FORMAT_VERSION {
+            return Err(ScriptError::UnsupportedVersion(format_version));
+        }
+    }
+
+    if let Ok(v1) = ron::from_str::<ScriptV1>(&text) {
+        return Ok(Script {
+            header: ScriptHeader {
+                format_version: SCRIPT_FORMAT_VERSION,
+                ..v1.header
+            },
+            lines: v1.inputs.into_iter().map(ScriptLine::Input).collect(),
+        });
+    }
+
+    ron::from_str::<Script>(&text).map_err(|err| ScriptError::Document(err.to_string()))
+}
+
+pub fn load_script_file(path: &Path) -> Result<Script, ScriptError> {
+    let file = File::open(path).map_err(|_| ScriptError::Io)?;
+    deser_script(&mut BufReader::new(file))
+}
+
+// a script with all includes replaced by the lines of the included scripts, ready to be played
+pub struct ExpandedScript {
+    // files[0] is the main script
+    pub files: Vec<PathBuf>,
+    pub lines: Vec<ScriptLine>,
+    // where each of the lines was written
+    pub origins: Vec<LineOrigin>,
+}
+
+#[derive(Clone)]
+pub struct LineOrigin {
+    // (file index, line index) from the include in the main script down to the line itself
+    pub chain: Vec<(usize, usize)>,
+}
+
+// paths are relative to the directory of the including script
+pub fn expand_includes(lines: &[ScriptLine], path: &Path) -> Result<ExpandedScript, ScriptError> {
+    let mut res = ExpandedScript {
+        files: vec![path.to_path_buf()],
+        lines: Vec::new(),
+        origins: Vec::new(),
+    };
+    let mut include_stack = vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())];
+    expand_into(&mut res, lines, 0, &[], &mut include_stack)?;
+    Ok(res)
+}
+
+fn expand_into(
+    res: &mut ExpandedScript,
+    lines: &[ScriptLine],
+    file: usize,
+    parent_chain: &[(usize, usize)],
+    include_stack: &mut Vec<PathBuf>,
+) -> Result<(), ScriptError> {
+    let path = res.files[file].clone();
+    ControlFlow::new(lines).map_err(|err| ScriptError::ControlFlow(path.clone(), err))?;
+
+    // times since the start of an included script would be wrong in the including one
+    let mut lines = lines.to_vec();
+    to_relative_timing(&mut lines);
+
+    // every included script has its own labels, so equal names in different files don't clash
+    let label = |name: &str| match file {
+        0 => name.to_string(),
+        _ => format!("{}/{}", file, name),
+    };
+
+    for (idx, line) in lines.into_iter().enumerate() {
+        let mut chain = parent_chain.to_vec();
+        chain.push((file, idx));
+
+        let line = match line {
+            ScriptLine::Include(include) => {
+                let include_path = path.parent().unwrap_or_else(|| Path::new("")).join(&include);
+                let canonical = include_path.canonicalize().unwrap_or_else(|_| include_path.clone());
+                if include_stack.contains(&canonical) {
+                    return Err(ScriptError::IncludeCycle(include_path));
+                }
+
+                let script = load_script_file(&include_path)
+                    .map_err(|err| ScriptError::Include(include_path.clone(), Box::new(err)))?;
+
+                res.files.push(include_path);
+                include_stack.push(canonical);
+                expand_into(res, &script.lines, res.files.len() - 1, &chain, include_stack)?;
+                include_stack.pop();
+                continue;
+            }
+            ScriptLine::Label(name) => ScriptLine::Label(label(&name)),
+            ScriptLine::Goto(name) => ScriptLine::Goto(label(&name)),
+            line => line,
+        };
+
+        res.lines.push(line);
+        res.origins.push(LineOrigin { chain });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> Result<Script, ScriptError> {
+        deser_script(&mut text.as_bytes())
+    }
+
+    #[test]
+    fn newer_document_is_unsupported() {
+        let text = "(header: (format_version: 99, new_field: 1), lines: [NewLine(1)], more: true)";
+        assert!(matches!(read(text), Err(ScriptError::UnsupportedVersion(99))));
+    }
+
+    #[test]
+    fn document_round_trip() {
+        let lines: Vec<ScriptLine> = ["0 tap 1 1", "repeat 2 {", "+10ms keyevent KEYCODE_BACK", "}"]
+            .iter()
+            .map(|line| line.parse().unwrap())
+            .collect();
+        let mut out = Vec::new();
+        serialize_script(&Script { header: ScriptHeader::default(), lines }, &mut out).ok().unwrap();
+        let script = read(std::str::from_utf8(&out).unwrap()).ok().unwrap();
+        assert_eq!(script.lines.len(), 4);
+        assert!(matches!(script.lines[1], ScriptLine::RepeatStart(2)));
+    }
+
+    #[test]
+    fn broken_document_is_not_read_as_lines() {
+        assert!(matches!(read("(header: (format_version: 6), lines: [Input("), Err(ScriptError::Document(_))));
+        assert!(matches!(read("0 tap 1 1\n+10ms tap 2 2"), Ok(script) if script.lines.len() == 2));
+    }
+}